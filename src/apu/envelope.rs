@@ -0,0 +1,63 @@
+const MAX_VOLUME: u8 = 15;
+
+// Volume envelope shared by the square and noise channels, clocked at 64 Hz
+pub struct Envelope {
+    register: u8,
+    volume: u8,
+    timer: u8,
+}
+
+impl Envelope {
+    pub fn new() -> Envelope {
+        Envelope {
+            register: 0,
+            volume: 0,
+            timer: 0,
+        }
+    }
+
+    pub fn read(&self) -> u8 {
+        self.register
+    }
+
+    pub fn write(&mut self, value: u8) {
+        self.register = value;
+    }
+
+    pub fn volume(&self) -> u8 {
+        self.volume
+    }
+
+    // The DAC is off when both the initial volume and the direction bit are 0
+    pub fn dac_enabled(&self) -> bool {
+        self.register & 0xF8 != 0
+    }
+
+    pub fn trigger(&mut self) {
+        self.volume = self.register >> 4;
+        self.timer = self.period();
+    }
+
+    pub fn clock(&mut self) {
+        let period = self.period();
+        if period == 0 {
+            return;
+        }
+        self.timer = self.timer.saturating_sub(1);
+        if self.timer > 0 {
+            return;
+        }
+        self.timer = period;
+        if self.register & 0x08 != 0 {
+            if self.volume < MAX_VOLUME {
+                self.volume += 1;
+            }
+        } else if self.volume > 0 {
+            self.volume -= 1;
+        }
+    }
+
+    fn period(&self) -> u8 {
+        self.register & 0x07
+    }
+}
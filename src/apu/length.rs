@@ -0,0 +1,45 @@
+// Silences a channel after a set number of 256 Hz clocks when enabled
+pub struct LengthCounter {
+    enabled: bool,
+    counter: u16,
+    // 64 for the square and noise channels, 256 for the wave channel
+    maximum: u16,
+}
+
+impl LengthCounter {
+    pub fn new(maximum: u16) -> LengthCounter {
+        LengthCounter {
+            enabled: false,
+            counter: 0,
+            maximum,
+        }
+    }
+
+    pub fn is_enabled(&self) -> bool {
+        self.enabled
+    }
+
+    pub fn set_enabled(&mut self, enabled: bool) {
+        self.enabled = enabled;
+    }
+
+    // The register holds how much of the maximum length has already elapsed
+    pub fn load(&mut self, value: u8) {
+        self.counter = self.maximum - (value as u16 % self.maximum);
+    }
+
+    pub fn trigger(&mut self) {
+        if self.counter == 0 {
+            self.counter = self.maximum;
+        }
+    }
+
+    // Returns true when the length ran out and the channel has to be turned off
+    pub fn clock(&mut self) -> bool {
+        if !self.enabled || self.counter == 0 {
+            return false;
+        }
+        self.counter -= 1;
+        self.counter == 0
+    }
+}
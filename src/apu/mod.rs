@@ -0,0 +1,325 @@
+mod envelope;
+mod length;
+mod noise;
+mod square;
+mod wave;
+
+use self::noise::NoiseChannel;
+use self::square::SquareChannel;
+use self::wave::WaveChannel;
+
+pub const NR10_REGISTER: u16 = 0xFF10;
+pub const NR50_REGISTER: u16 = 0xFF24;
+pub const NR51_REGISTER: u16 = 0xFF25;
+pub const NR52_REGISTER: u16 = 0xFF26;
+pub const WAVE_RAM_START: u16 = 0xFF30;
+pub const WAVE_RAM_END: u16 = 0xFF3F;
+
+// Each channel has five registers starting at these addresses
+const CHANNEL_1_START: u16 = 0xFF10;
+const CHANNEL_1_END: u16 = 0xFF14;
+const CHANNEL_2_START: u16 = 0xFF15;
+const CHANNEL_2_END: u16 = 0xFF19;
+const CHANNEL_3_START: u16 = 0xFF1A;
+const CHANNEL_3_END: u16 = 0xFF1E;
+const CHANNEL_4_START: u16 = 0xFF1F;
+const CHANNEL_4_END: u16 = 0xFF23;
+
+const NR52_POWER: u8 = 0x80;
+
+pub const CPU_FREQUENCY: u32 = 4_194_304;
+pub const DEFAULT_SAMPLE_RATE: u32 = 48_000;
+// The frame sequencer runs at 512 Hz
+const FRAME_SEQUENCER_PERIOD: u32 = CPU_FREQUENCY / 512;
+// Charge factor of the high-pass capacitor per T-cycle, which removes the DC offset of the DACs
+const HIGH_PASS_CHARGE: f64 = 0.999958;
+// Hosts that stop pulling samples lose the oldest ones past this much audio
+const MAX_BUFFERED_SECONDS: usize = 4;
+
+pub struct Apu {
+    powered: bool,
+    channel1: SquareChannel,
+    channel2: SquareChannel,
+    channel3: WaveChannel,
+    channel4: NoiseChannel,
+    nr50: u8,
+    nr51: u8,
+    frame_sequencer_cycles: u32,
+    frame_sequencer_step: u8,
+    sample_rate: u32,
+    // T-cycles since the last sample, with fractions carried over
+    sample_cycles: f64,
+    high_pass_charge: f32,
+    capacitors: [f32; 2],
+    // Interleaved left and right samples waiting to be pulled by the host
+    samples: Vec<f32>,
+    // Only filled while something asked for the channels separately
+    channel_capture: Option<ChannelCapture>,
+}
+
+// Unmixed mono output of each channel, before panning and master volume
+struct ChannelCapture {
+    samples: [Vec<f32>; 4],
+    capacitors: [f32; 4],
+}
+
+impl Apu {
+    pub fn new() -> Apu {
+        let mut apu = Apu {
+            powered: true,
+            channel1: SquareChannel::new(true),
+            channel2: SquareChannel::new(false),
+            channel3: WaveChannel::new(),
+            channel4: NoiseChannel::new(),
+            nr50: 0x77,
+            nr51: 0xF3,
+            frame_sequencer_cycles: 0,
+            frame_sequencer_step: 0,
+            sample_rate: DEFAULT_SAMPLE_RATE,
+            sample_cycles: 0.0,
+            high_pass_charge: 0.0,
+            capacitors: [0.0; 2],
+            samples: Vec::new(),
+            channel_capture: None,
+        };
+        apu.set_sample_rate(DEFAULT_SAMPLE_RATE);
+        apu
+    }
+
+    pub fn sample_rate(&self) -> u32 {
+        self.sample_rate
+    }
+
+    pub fn set_sample_rate(&mut self, sample_rate: u32) {
+        self.sample_rate = sample_rate.max(1);
+        self.high_pass_charge =
+            HIGH_PASS_CHARGE.powf(CPU_FREQUENCY as f64 / self.sample_rate as f64) as f32;
+    }
+
+    // Interleaved stereo samples in -1.0..=1.0 produced since the last call, or the
+    // latest few seconds of them when the host fell behind
+    pub fn take_samples(&mut self) -> Vec<f32> {
+        std::mem::take(&mut self.samples)
+    }
+
+    pub fn set_channel_capture(&mut self, enabled: bool) {
+        self.channel_capture = enabled.then(|| ChannelCapture {
+            samples: Default::default(),
+            capacitors: [0.0; 4],
+        });
+    }
+
+    // Mono samples of each channel produced since the last call, in step with take_samples
+    pub fn take_channel_samples(&mut self) -> Option<[Vec<f32>; 4]> {
+        self.channel_capture
+            .as_mut()
+            .map(|capture| std::mem::take(&mut capture.samples))
+    }
+
+    // Advances by the given number of T-cycles at normal speed
+    pub fn tick(&mut self, cycles: u32) {
+        if self.powered {
+            self.channel1.tick(cycles);
+            self.channel2.tick(cycles);
+            self.channel3.tick(cycles);
+            self.channel4.tick(cycles);
+
+            self.frame_sequencer_cycles += cycles;
+            while self.frame_sequencer_cycles >= FRAME_SEQUENCER_PERIOD {
+                self.frame_sequencer_cycles -= FRAME_SEQUENCER_PERIOD;
+                self.step_frame_sequencer();
+            }
+        }
+
+        let sample_period = CPU_FREQUENCY as f64 / self.sample_rate as f64;
+        self.sample_cycles += cycles as f64;
+        while self.sample_cycles >= sample_period {
+            self.sample_cycles -= sample_period;
+            self.push_sample();
+        }
+    }
+
+    // Length runs on even steps at 256 Hz, sweep on steps 2 and 6 at 128 Hz
+    // and the envelopes on step 7 at 64 Hz
+    fn step_frame_sequencer(&mut self) {
+        let step = self.frame_sequencer_step;
+        if step % 2 == 0 {
+            self.channel1.clock_length();
+            self.channel2.clock_length();
+            self.channel3.clock_length();
+            self.channel4.clock_length();
+        }
+        if step == 2 || step == 6 {
+            self.channel1.clock_sweep();
+        }
+        if step == 7 {
+            self.channel1.clock_envelope();
+            self.channel2.clock_envelope();
+            self.channel4.clock_envelope();
+        }
+        self.frame_sequencer_step = (step + 1) % 8;
+    }
+
+    // Analog output of each channel's DAC in -1.0..=1.0, or 0 when the DAC is off
+    fn channel_outputs(&self) -> [f32; 4] {
+        let dac = |enabled: bool, digital: u8| {
+            if enabled {
+                digital as f32 / 7.5 - 1.0
+            } else {
+                0.0
+            }
+        };
+        [
+            dac(self.channel1.dac_enabled(), self.channel1.output()),
+            dac(self.channel2.dac_enabled(), self.channel2.output()),
+            dac(self.channel3.dac_enabled(), self.channel3.output()),
+            dac(self.channel4.dac_enabled(), self.channel4.output()),
+        ]
+    }
+
+    fn push_sample(&mut self) {
+        let outputs = if self.powered {
+            self.channel_outputs()
+        } else {
+            [0.0; 4]
+        };
+
+        // NR51 routes channels to the left (upper nibble) and right (lower nibble) outputs,
+        // NR50 then scales each side by 1-8
+        let mut mixed = [0.0f32; 2];
+        for (side, sample) in mixed.iter_mut().enumerate() {
+            let shift = if side == 0 { 4 } else { 0 };
+            let routed: f32 = outputs
+                .iter()
+                .enumerate()
+                .filter(|(channel, _)| self.nr51 & (1 << (channel + shift)) != 0)
+                .map(|(_, output)| output)
+                .sum();
+            let volume = ((self.nr50 >> shift) & 0x07) as f32 + 1.0;
+            *sample = routed / 4.0 * volume / 8.0;
+        }
+
+        self.drop_stale_samples();
+        for (side, sample) in mixed.into_iter().enumerate() {
+            let filtered = sample - self.capacitors[side];
+            self.capacitors[side] = sample - filtered * self.high_pass_charge;
+            self.samples.push(filtered);
+        }
+
+        if let Some(capture) = &mut self.channel_capture {
+            for (channel, &output) in outputs.iter().enumerate() {
+                let filtered = output - capture.capacitors[channel];
+                capture.capacitors[channel] = output - filtered * self.high_pass_charge;
+                capture.samples[channel].push(filtered);
+            }
+        }
+    }
+
+    // Throws away the older half once the buffer is full, rather than a sample at a time
+    fn drop_stale_samples(&mut self) {
+        let limit = self.sample_rate as usize * MAX_BUFFERED_SECONDS;
+        if self.samples.len() / 2 < limit {
+            return;
+        }
+        let dropped = limit / 2;
+        self.samples.drain(..dropped * 2);
+        if let Some(capture) = &mut self.channel_capture {
+            for samples in &mut capture.samples {
+                samples.drain(..dropped.min(samples.len()));
+            }
+        }
+    }
+
+    pub fn read(&self, address: u16) -> u8 {
+        match address {
+            CHANNEL_1_START..=CHANNEL_1_END => self.channel1.read(address - CHANNEL_1_START),
+            CHANNEL_2_START..=CHANNEL_2_END => self.channel2.read(address - CHANNEL_2_START),
+            CHANNEL_3_START..=CHANNEL_3_END => self.channel3.read(address - CHANNEL_3_START),
+            CHANNEL_4_START..=CHANNEL_4_END => self.channel4.read(address - CHANNEL_4_START),
+            NR50_REGISTER => self.nr50,
+            NR51_REGISTER => self.nr51,
+            NR52_REGISTER => {
+                let status = self.channel1.is_enabled() as u8
+                    | (self.channel2.is_enabled() as u8) << 1
+                    | (self.channel3.is_enabled() as u8) << 2
+                    | (self.channel4.is_enabled() as u8) << 3;
+                (self.powered as u8) << 7 | 0x70 | status
+            }
+            WAVE_RAM_START..=WAVE_RAM_END => {
+                self.channel3.read_ram((address - WAVE_RAM_START) as usize)
+            }
+            _ => 0xFF,
+        }
+    }
+
+    pub fn write(&mut self, address: u16, value: u8) {
+        // Wave RAM stays accessible while the APU is off, the registers don't
+        if let WAVE_RAM_START..=WAVE_RAM_END = address {
+            self.channel3
+                .write_ram((address - WAVE_RAM_START) as usize, value);
+            return;
+        }
+        if address == NR52_REGISTER {
+            self.write_power(value & NR52_POWER != 0);
+            return;
+        }
+        if !self.powered {
+            return;
+        }
+        match address {
+            CHANNEL_1_START..=CHANNEL_1_END => {
+                self.channel1.write(address - CHANNEL_1_START, value)
+            }
+            CHANNEL_2_START..=CHANNEL_2_END => {
+                self.channel2.write(address - CHANNEL_2_START, value)
+            }
+            CHANNEL_3_START..=CHANNEL_3_END => {
+                self.channel3.write(address - CHANNEL_3_START, value)
+            }
+            CHANNEL_4_START..=CHANNEL_4_END => {
+                self.channel4.write(address - CHANNEL_4_START, value)
+            }
+            NR50_REGISTER => self.nr50 = value,
+            NR51_REGISTER => self.nr51 = value,
+            _ => {}
+        }
+    }
+
+    // Powering off clears every register except wave RAM
+    fn write_power(&mut self, powered: bool) {
+        if self.powered && !powered {
+            self.channel1 = SquareChannel::new(true);
+            self.channel2 = SquareChannel::new(false);
+            self.channel3 = WaveChannel::with_ram(self.channel3.ram());
+            self.channel4 = NoiseChannel::new();
+            self.nr50 = 0;
+            self.nr51 = 0;
+        } else if !self.powered && powered {
+            self.frame_sequencer_step = 0;
+            self.frame_sequencer_cycles = 0;
+        }
+        self.powered = powered;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn unread_samples_stay_bounded() {
+        let mut apu = Apu::new();
+        apu.set_sample_rate(8_000);
+        apu.set_channel_capture(true);
+        for _ in 0..(MAX_BUFFERED_SECONDS + 2) * 256 {
+            apu.tick(CPU_FREQUENCY / 256);
+        }
+        let samples = apu.take_samples();
+        assert!(samples.len() <= 8_000 * MAX_BUFFERED_SECONDS * 2);
+        assert!(samples.len() % 2 == 0);
+        let channels = apu.take_channel_samples().unwrap();
+        assert!(channels
+            .iter()
+            .all(|channel| channel.len() == samples.len() / 2));
+    }
+}
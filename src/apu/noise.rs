@@ -0,0 +1,109 @@
+use super::envelope::Envelope;
+use super::length::LengthCounter;
+
+const DIVISORS: [u32; 8] = [8, 16, 32, 48, 64, 80, 96, 112];
+const LFSR_SEED: u16 = 0x7FFF;
+
+// Channel 4, pseudo-random noise from a linear feedback shift register
+pub struct NoiseChannel {
+    enabled: bool,
+    length: LengthCounter,
+    envelope: Envelope,
+    polynomial: u8,
+    timer: u32,
+    lfsr: u16,
+}
+
+impl NoiseChannel {
+    pub fn new() -> NoiseChannel {
+        NoiseChannel {
+            enabled: false,
+            length: LengthCounter::new(64),
+            envelope: Envelope::new(),
+            polynomial: 0,
+            timer: 0,
+            lfsr: LFSR_SEED,
+        }
+    }
+
+    pub fn is_enabled(&self) -> bool {
+        self.enabled
+    }
+
+    pub fn read(&self, register: u16) -> u8 {
+        match register {
+            2 => self.envelope.read(),
+            3 => self.polynomial,
+            4 => (self.length.is_enabled() as u8) << 6 | 0xBF,
+            _ => 0xFF,
+        }
+    }
+
+    pub fn write(&mut self, register: u16, value: u8) {
+        match register {
+            1 => self.length.load(value & 0x3F),
+            2 => {
+                self.envelope.write(value);
+                if !self.envelope.dac_enabled() {
+                    self.enabled = false;
+                }
+            }
+            3 => self.polynomial = value,
+            4 => {
+                self.length.set_enabled(value & 0x40 != 0);
+                if value & 0x80 != 0 {
+                    self.enabled = self.envelope.dac_enabled();
+                    self.length.trigger();
+                    self.envelope.trigger();
+                    self.timer = self.period();
+                    self.lfsr = LFSR_SEED;
+                }
+            }
+            _ => {}
+        }
+    }
+
+    fn period(&self) -> u32 {
+        DIVISORS[(self.polynomial & 0x07) as usize] << (self.polynomial >> 4)
+    }
+
+    pub fn tick(&mut self, cycles: u32) {
+        if !self.enabled {
+            return;
+        }
+        let mut cycles = cycles;
+        while cycles >= self.timer {
+            cycles -= self.timer;
+            self.timer = self.period();
+            let feedback = (self.lfsr & 0b1) ^ ((self.lfsr >> 1) & 0b1);
+            self.lfsr = (self.lfsr >> 1) | (feedback << 14);
+            // Width mode also feeds bit 6, giving a short 7-bit sequence
+            if self.polynomial & 0x08 != 0 {
+                self.lfsr = (self.lfsr & !(1 << 6)) | (feedback << 6);
+            }
+        }
+        self.timer -= cycles;
+    }
+
+    pub fn clock_length(&mut self) {
+        if self.length.clock() {
+            self.enabled = false;
+        }
+    }
+
+    pub fn clock_envelope(&mut self) {
+        self.envelope.clock();
+    }
+
+    pub fn dac_enabled(&self) -> bool {
+        self.envelope.dac_enabled()
+    }
+
+    // Digital output, 0-15
+    pub fn output(&self) -> u8 {
+        if !self.enabled || self.lfsr & 0b1 != 0 {
+            return 0;
+        }
+        self.envelope.volume()
+    }
+}
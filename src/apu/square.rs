@@ -0,0 +1,211 @@
+use super::envelope::Envelope;
+use super::length::LengthCounter;
+
+const DUTY_PATTERNS: [[u8; 8]; 4] = [
+    [0, 0, 0, 0, 0, 0, 0, 1], // 12.5%
+    [1, 0, 0, 0, 0, 0, 0, 1], // 25%
+    [1, 0, 0, 0, 0, 1, 1, 1], // 50%
+    [0, 1, 1, 1, 1, 1, 1, 0], // 75%
+];
+
+const MAX_FREQUENCY: u16 = 2047;
+// A sweep period of 0 is treated as 8 by the sweep timer
+const SWEEP_ZERO_PERIOD: u8 = 8;
+
+struct Sweep {
+    register: u8,
+    timer: u8,
+    enabled: bool,
+    // Working copy of the frequency the sweep calculations run on
+    shadow_frequency: u16,
+}
+
+impl Sweep {
+    fn period(&self) -> u8 {
+        (self.register >> 4) & 0x07
+    }
+
+    fn negate(&self) -> bool {
+        self.register & 0x08 != 0
+    }
+
+    fn shift(&self) -> u8 {
+        self.register & 0x07
+    }
+
+    fn reload_timer(&mut self) {
+        self.timer = match self.period() {
+            0 => SWEEP_ZERO_PERIOD,
+            period => period,
+        };
+    }
+
+    // Returns None when the new frequency overflows, which turns the channel off
+    fn next_frequency(&self) -> Option<u16> {
+        let delta = self.shadow_frequency >> self.shift();
+        let frequency = if self.negate() {
+            self.shadow_frequency - delta
+        } else {
+            self.shadow_frequency + delta
+        };
+        (frequency <= MAX_FREQUENCY).then_some(frequency)
+    }
+}
+
+// Channels 1 and 2. Only channel 1 has the frequency sweep.
+pub struct SquareChannel {
+    enabled: bool,
+    sweep: Option<Sweep>,
+    duty: u8,
+    length: LengthCounter,
+    envelope: Envelope,
+    frequency: u16,
+    timer: u32,
+    duty_position: usize,
+}
+
+impl SquareChannel {
+    pub fn new(with_sweep: bool) -> SquareChannel {
+        SquareChannel {
+            enabled: false,
+            sweep: with_sweep.then_some(Sweep {
+                register: 0,
+                timer: 0,
+                enabled: false,
+                shadow_frequency: 0,
+            }),
+            duty: 0,
+            length: LengthCounter::new(64),
+            envelope: Envelope::new(),
+            frequency: 0,
+            timer: 0,
+            duty_position: 0,
+        }
+    }
+
+    pub fn is_enabled(&self) -> bool {
+        self.enabled
+    }
+
+    // Registers are numbered 0-4 from NRx0 to NRx4; unreadable bits come back as 1
+    pub fn read(&self, register: u16) -> u8 {
+        match register {
+            0 => self
+                .sweep
+                .as_ref()
+                .map_or(0xFF, |sweep| sweep.register | 0x80),
+            1 => self.duty << 6 | 0x3F,
+            2 => self.envelope.read(),
+            4 => (self.length.is_enabled() as u8) << 6 | 0xBF,
+            _ => 0xFF,
+        }
+    }
+
+    pub fn write(&mut self, register: u16, value: u8) {
+        match register {
+            0 => {
+                if let Some(sweep) = &mut self.sweep {
+                    sweep.register = value & 0x7F;
+                }
+            }
+            1 => {
+                self.duty = value >> 6;
+                self.length.load(value & 0x3F);
+            }
+            2 => {
+                self.envelope.write(value);
+                if !self.envelope.dac_enabled() {
+                    self.enabled = false;
+                }
+            }
+            3 => self.frequency = (self.frequency & 0x0700) | value as u16,
+            4 => {
+                self.frequency = (self.frequency & 0x00FF) | ((value as u16 & 0x07) << 8);
+                self.length.set_enabled(value & 0x40 != 0);
+                if value & 0x80 != 0 {
+                    self.trigger();
+                }
+            }
+            _ => {}
+        }
+    }
+
+    fn trigger(&mut self) {
+        self.enabled = self.envelope.dac_enabled();
+        self.length.trigger();
+        self.envelope.trigger();
+        self.timer = self.period();
+
+        if let Some(sweep) = &mut self.sweep {
+            sweep.shadow_frequency = self.frequency;
+            sweep.reload_timer();
+            sweep.enabled = sweep.period() != 0 || sweep.shift() != 0;
+            // The overflow check runs right away when there's a shift
+            if sweep.shift() != 0 && sweep.next_frequency().is_none() {
+                self.enabled = false;
+            }
+        }
+    }
+
+    fn period(&self) -> u32 {
+        (2048 - self.frequency as u32) * 4
+    }
+
+    pub fn tick(&mut self, cycles: u32) {
+        let mut cycles = cycles;
+        while cycles >= self.timer {
+            cycles -= self.timer;
+            self.timer = self.period();
+            self.duty_position = (self.duty_position + 1) % 8;
+        }
+        self.timer -= cycles;
+    }
+
+    pub fn clock_length(&mut self) {
+        if self.length.clock() {
+            self.enabled = false;
+        }
+    }
+
+    pub fn clock_envelope(&mut self) {
+        self.envelope.clock();
+    }
+
+    pub fn clock_sweep(&mut self) {
+        let Some(sweep) = &mut self.sweep else {
+            return;
+        };
+        sweep.timer = sweep.timer.saturating_sub(1);
+        if sweep.timer > 0 {
+            return;
+        }
+        sweep.reload_timer();
+        if !sweep.enabled || sweep.period() == 0 {
+            return;
+        }
+        match sweep.next_frequency() {
+            Some(frequency) if sweep.shift() != 0 => {
+                sweep.shadow_frequency = frequency;
+                self.frequency = frequency;
+                // The new frequency is checked for overflow again without being used
+                if sweep.next_frequency().is_none() {
+                    self.enabled = false;
+                }
+            }
+            Some(_) => {}
+            None => self.enabled = false,
+        }
+    }
+
+    pub fn dac_enabled(&self) -> bool {
+        self.envelope.dac_enabled()
+    }
+
+    // Digital output, 0-15
+    pub fn output(&self) -> u8 {
+        if !self.enabled {
+            return 0;
+        }
+        DUTY_PATTERNS[self.duty as usize][self.duty_position] * self.envelope.volume()
+    }
+}
@@ -0,0 +1,132 @@
+use super::length::LengthCounter;
+
+pub const WAVE_RAM_SIZE: usize = 16;
+const SAMPLE_COUNT: usize = WAVE_RAM_SIZE * 2;
+
+// Channel 3, which plays back 32 4-bit samples from wave RAM
+pub struct WaveChannel {
+    enabled: bool,
+    dac_enabled: bool,
+    length: LengthCounter,
+    volume_code: u8,
+    frequency: u16,
+    timer: u32,
+    position: usize,
+    sample: u8,
+    ram: [u8; WAVE_RAM_SIZE],
+}
+
+impl WaveChannel {
+    pub fn new() -> WaveChannel {
+        WaveChannel::with_ram([0; WAVE_RAM_SIZE])
+    }
+
+    pub fn with_ram(ram: [u8; WAVE_RAM_SIZE]) -> WaveChannel {
+        WaveChannel {
+            enabled: false,
+            dac_enabled: false,
+            length: LengthCounter::new(256),
+            volume_code: 0,
+            frequency: 0,
+            timer: 0,
+            position: 0,
+            sample: 0,
+            ram,
+        }
+    }
+
+    pub fn is_enabled(&self) -> bool {
+        self.enabled
+    }
+
+    pub fn read(&self, register: u16) -> u8 {
+        match register {
+            0 => (self.dac_enabled as u8) << 7 | 0x7F,
+            2 => self.volume_code << 5 | 0x9F,
+            4 => (self.length.is_enabled() as u8) << 6 | 0xBF,
+            _ => 0xFF,
+        }
+    }
+
+    pub fn write(&mut self, register: u16, value: u8) {
+        match register {
+            0 => {
+                self.dac_enabled = value & 0x80 != 0;
+                if !self.dac_enabled {
+                    self.enabled = false;
+                }
+            }
+            1 => self.length.load(value),
+            2 => self.volume_code = (value >> 5) & 0x03,
+            3 => self.frequency = (self.frequency & 0x0700) | value as u16,
+            4 => {
+                self.frequency = (self.frequency & 0x00FF) | ((value as u16 & 0x07) << 8);
+                self.length.set_enabled(value & 0x40 != 0);
+                if value & 0x80 != 0 {
+                    self.enabled = self.dac_enabled;
+                    self.length.trigger();
+                    self.timer = self.period();
+                    self.position = 0;
+                }
+            }
+            _ => {}
+        }
+    }
+
+    pub fn ram(&self) -> [u8; WAVE_RAM_SIZE] {
+        self.ram
+    }
+
+    pub fn read_ram(&self, index: usize) -> u8 {
+        self.ram[index]
+    }
+
+    pub fn write_ram(&mut self, index: usize, value: u8) {
+        self.ram[index] = value;
+    }
+
+    fn period(&self) -> u32 {
+        (2048 - self.frequency as u32) * 2
+    }
+
+    pub fn tick(&mut self, cycles: u32) {
+        if !self.enabled {
+            return;
+        }
+        let mut cycles = cycles;
+        while cycles >= self.timer {
+            cycles -= self.timer;
+            self.timer = self.period();
+            self.position = (self.position + 1) % SAMPLE_COUNT;
+            // Samples are packed two per byte, high nibble first
+            let byte = self.ram[self.position / 2];
+            self.sample = if self.position % 2 == 0 {
+                byte >> 4
+            } else {
+                byte & 0x0F
+            };
+        }
+        self.timer -= cycles;
+    }
+
+    pub fn clock_length(&mut self) {
+        if self.length.clock() {
+            self.enabled = false;
+        }
+    }
+
+    pub fn dac_enabled(&self) -> bool {
+        self.dac_enabled
+    }
+
+    // Digital output, 0-15, after the volume shift (mute, 100%, 50%, 25%)
+    pub fn output(&self) -> u8 {
+        if !self.enabled {
+            return 0;
+        }
+        match self.volume_code {
+            0 => 0,
+            code => self.sample >> (code - 1),
+        }
+    }
+}
@@ -0,0 +1,388 @@
+use super::CartridgeError;
+
+const HEADER_END: usize = 0x014F;
+
+const LOGO_START: usize = 0x0104;
+const LOGO_END: usize = 0x0133;
+const TITLE_START: usize = 0x0134;
+const TITLE_END: usize = 0x0143;
+const NEW_LICENSEE_CODE_START: usize = 0x0144;
+const NEW_LICENSEE_CODE_END: usize = 0x0145;
+const CGB_FLAG_ADDRESS: usize = 0x0143;
+const SGB_FLAG_ADDRESS: usize = 0x0146;
+const CARTRIDGE_TYPE_ADDRESS: usize = 0x0147;
+const ROM_SIZE_ADDRESS: usize = 0x0148;
+const RAM_SIZE_ADDRESS: usize = 0x0149;
+const DESTINATION_CODE_ADDRESS: usize = 0x014A;
+const OLD_LICENSEE_CODE_ADDRESS: usize = 0x014B;
+const MASK_ROM_VERSION_ADDRESS: usize = 0x014C;
+const HEADER_CHECKSUM_ADDRESS: usize = 0x014D;
+const GLOBAL_CHECKSUM_ADDRESS: usize = 0x014E;
+
+// An old licensee code of 0x33 means the licensee is stored in the two-character new code instead
+const USE_NEW_LICENSEE_CODE: u8 = 0x33;
+
+pub const NINTENDO_LOGO: [u8; 48] = [
+    0xCE, 0xED, 0x66, 0x66, 0xCC, 0x0D, 0x00, 0x0B, 0x03, 0x73, 0x00, 0x83, 0x00, 0x0C, 0x00, 0x0D,
+    0x00, 0x08, 0x11, 0x1F, 0x88, 0x89, 0x00, 0x0E, 0xDC, 0xCC, 0x6E, 0xE6, 0xDD, 0xDD, 0xD9, 0x99,
+    0xBB, 0xBB, 0x67, 0x63, 0x6E, 0x0E, 0xEC, 0xCC, 0xDD, 0xDC, 0x99, 0x9F, 0xBB, 0xB9, 0x33, 0x3E,
+];
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum CartridgeType {
+    RomOnly,
+    Mbc1,
+    Mbc1Ram,
+    Mbc1RamBattery,
+    Mbc2,
+    Mbc2Battery,
+    RomRam,
+    RomRamBattery,
+    Mmm01,
+    Mmm01Ram,
+    Mmm01RamBattery,
+    Mbc3TimerBattery,
+    Mbc3TimerRamBattery,
+    Mbc3,
+    Mbc3Ram,
+    Mbc3RamBattery,
+    Mbc5,
+    Mbc5Ram,
+    Mbc5RamBattery,
+    Mbc5Rumble,
+    Mbc5RumbleRam,
+    Mbc5RumbleRamBattery,
+    Mbc6,
+    Mbc7SensorRumbleRamBattery,
+    PocketCamera,
+    BandaiTama5,
+    HuC3,
+    HuC1RamBattery,
+}
+
+impl CartridgeType {
+    pub fn from_byte(byte: u8) -> Option<CartridgeType> {
+        match byte {
+            0x00 => Some(CartridgeType::RomOnly),
+            0x01 => Some(CartridgeType::Mbc1),
+            0x02 => Some(CartridgeType::Mbc1Ram),
+            0x03 => Some(CartridgeType::Mbc1RamBattery),
+            0x05 => Some(CartridgeType::Mbc2),
+            0x06 => Some(CartridgeType::Mbc2Battery),
+            0x08 => Some(CartridgeType::RomRam),
+            0x09 => Some(CartridgeType::RomRamBattery),
+            0x0B => Some(CartridgeType::Mmm01),
+            0x0C => Some(CartridgeType::Mmm01Ram),
+            0x0D => Some(CartridgeType::Mmm01RamBattery),
+            0x0F => Some(CartridgeType::Mbc3TimerBattery),
+            0x10 => Some(CartridgeType::Mbc3TimerRamBattery),
+            0x11 => Some(CartridgeType::Mbc3),
+            0x12 => Some(CartridgeType::Mbc3Ram),
+            0x13 => Some(CartridgeType::Mbc3RamBattery),
+            0x19 => Some(CartridgeType::Mbc5),
+            0x1A => Some(CartridgeType::Mbc5Ram),
+            0x1B => Some(CartridgeType::Mbc5RamBattery),
+            0x1C => Some(CartridgeType::Mbc5Rumble),
+            0x1D => Some(CartridgeType::Mbc5RumbleRam),
+            0x1E => Some(CartridgeType::Mbc5RumbleRamBattery),
+            0x20 => Some(CartridgeType::Mbc6),
+            0x22 => Some(CartridgeType::Mbc7SensorRumbleRamBattery),
+            0xFC => Some(CartridgeType::PocketCamera),
+            0xFD => Some(CartridgeType::BandaiTama5),
+            0xFE => Some(CartridgeType::HuC3),
+            0xFF => Some(CartridgeType::HuC1RamBattery),
+            _ => None,
+        }
+    }
+
+    pub fn has_battery(&self) -> bool {
+        matches!(
+            self,
+            CartridgeType::Mbc1RamBattery
+                | CartridgeType::Mbc2Battery
+                | CartridgeType::RomRamBattery
+                | CartridgeType::Mmm01RamBattery
+                | CartridgeType::Mbc3TimerBattery
+                | CartridgeType::Mbc3TimerRamBattery
+                | CartridgeType::Mbc3RamBattery
+                | CartridgeType::Mbc5RamBattery
+                | CartridgeType::Mbc5RumbleRamBattery
+                | CartridgeType::Mbc7SensorRumbleRamBattery
+                | CartridgeType::HuC3
+                | CartridgeType::HuC1RamBattery
+        )
+    }
+
+    pub fn has_timer(&self) -> bool {
+        matches!(
+            self,
+            CartridgeType::Mbc3TimerBattery | CartridgeType::Mbc3TimerRamBattery
+        )
+    }
+
+    pub fn has_rumble(&self) -> bool {
+        matches!(
+            self,
+            CartridgeType::Mbc5Rumble
+                | CartridgeType::Mbc5RumbleRam
+                | CartridgeType::Mbc5RumbleRamBattery
+                | CartridgeType::Mbc7SensorRumbleRamBattery
+        )
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum CgbSupport {
+    // Monochrome-only cartridge; the CGB runs it in compatibility mode
+    None,
+    // Runs on both DMG and CGB, using CGB features when available
+    Enhanced,
+    // Refuses to run on anything but a CGB
+    Only,
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum Licensee {
+    Old(u8),
+    New(String),
+}
+
+#[derive(Clone, Debug)]
+pub struct CartridgeHeader {
+    pub title: String,
+    pub cgb_support: CgbSupport,
+    pub sgb_support: bool,
+    pub cartridge_type: CartridgeType,
+    pub rom_size: usize,
+    pub ram_size: usize,
+    pub licensee: Licensee,
+    pub japanese: bool,
+    pub mask_rom_version: u8,
+    pub header_checksum: u8,
+    pub global_checksum: u16,
+}
+
+impl CartridgeHeader {
+    pub fn parse(rom: &[u8]) -> Result<CartridgeHeader, CartridgeError> {
+        if rom.len() <= HEADER_END {
+            return Err(CartridgeError::TooSmall(rom.len()));
+        }
+
+        if !logo_matches(rom, 0) {
+            return Err(CartridgeError::InvalidLogo);
+        }
+
+        let computed_header_checksum = header_checksum(rom);
+        let header_checksum = rom[HEADER_CHECKSUM_ADDRESS];
+        if computed_header_checksum != header_checksum {
+            return Err(CartridgeError::HeaderChecksumMismatch {
+                expected: header_checksum,
+                computed: computed_header_checksum,
+            });
+        }
+
+        let cgb_support = match rom[CGB_FLAG_ADDRESS] {
+            0x80 => CgbSupport::Enhanced,
+            0xC0 => CgbSupport::Only,
+            _ => CgbSupport::None,
+        };
+
+        // CGB-aware cartridges reuse the last title byte as the CGB flag
+        let title_end = if cgb_support == CgbSupport::None {
+            TITLE_END
+        } else {
+            TITLE_END - 1
+        };
+        let title = rom[TITLE_START..=title_end]
+            .iter()
+            .take_while(|&&byte| byte != 0x00)
+            .map(|&byte| byte as char)
+            .collect::<String>()
+            .trim_end()
+            .to_string();
+
+        let cartridge_type_byte = rom[CARTRIDGE_TYPE_ADDRESS];
+        let cartridge_type = CartridgeType::from_byte(cartridge_type_byte).ok_or(
+            CartridgeError::UnsupportedCartridgeType(cartridge_type_byte),
+        )?;
+
+        let rom_size_code = rom[ROM_SIZE_ADDRESS];
+        let rom_size = rom_size_from_code(rom_size_code)
+            .ok_or(CartridgeError::InvalidRomSize(rom_size_code))?;
+
+        let ram_size_code = rom[RAM_SIZE_ADDRESS];
+        let ram_size = ram_size_from_code(ram_size_code)
+            .ok_or(CartridgeError::InvalidRamSize(ram_size_code))?;
+
+        let licensee = match rom[OLD_LICENSEE_CODE_ADDRESS] {
+            USE_NEW_LICENSEE_CODE => Licensee::New(
+                rom[NEW_LICENSEE_CODE_START..=NEW_LICENSEE_CODE_END]
+                    .iter()
+                    .map(|&byte| byte as char)
+                    .collect(),
+            ),
+            code => Licensee::Old(code),
+        };
+
+        Ok(CartridgeHeader {
+            title,
+            cgb_support,
+            sgb_support: rom[SGB_FLAG_ADDRESS] == 0x03,
+            cartridge_type,
+            rom_size,
+            ram_size,
+            licensee,
+            japanese: rom[DESTINATION_CODE_ADDRESS] == 0x00,
+            mask_rom_version: rom[MASK_ROM_VERSION_ADDRESS],
+            header_checksum,
+            global_checksum: (rom[GLOBAL_CHECKSUM_ADDRESS] as u16) << 8
+                | rom[GLOBAL_CHECKSUM_ADDRESS + 1] as u16,
+        })
+    }
+
+    // The global checksum is never verified by the hardware, so plenty of
+    // otherwise good dumps get it wrong. It is exposed but not enforced.
+    pub fn global_checksum_matches(&self, rom: &[u8]) -> bool {
+        global_checksum(rom) == self.global_checksum
+    }
+}
+
+// Checks for the logo of a header starting at `base`, which lets multicarts be
+// probed for the headers of the games packed behind the menu
+pub fn logo_matches(rom: &[u8], base: usize) -> bool {
+    rom.get(base + LOGO_START..=base + LOGO_END) == Some(&NINTENDO_LOGO[..])
+}
+
+// Same algorithm the boot ROM uses: x = x - rom[i] - 1 over 0x0134..=0x014C
+pub fn header_checksum(rom: &[u8]) -> u8 {
+    rom[TITLE_START..=MASK_ROM_VERSION_ADDRESS]
+        .iter()
+        .fold(0u8, |checksum, &byte| {
+            checksum.wrapping_sub(byte).wrapping_sub(1)
+        })
+}
+
+// Sum of every byte in the ROM except the two checksum bytes themselves
+pub fn global_checksum(rom: &[u8]) -> u16 {
+    rom.iter()
+        .enumerate()
+        .filter(|&(address, _)| {
+            address != GLOBAL_CHECKSUM_ADDRESS && address != GLOBAL_CHECKSUM_ADDRESS + 1
+        })
+        .fold(0u16, |checksum, (_, &byte)| {
+            checksum.wrapping_add(byte as u16)
+        })
+}
+
+fn rom_size_from_code(code: u8) -> Option<usize> {
+    match code {
+        0x00..=0x08 => Some((32 * 1024) << code),
+        0x52 => Some(72 * 16 * 1024),
+        0x53 => Some(80 * 16 * 1024),
+        0x54 => Some(96 * 16 * 1024),
+        _ => None,
+    }
+}
+
+fn ram_size_from_code(code: u8) -> Option<usize> {
+    match code {
+        0x00 => Some(0),
+        // Listed in some unofficial docs as 2 KiB, but never used by a licensed cartridge
+        0x01 => Some(2 * 1024),
+        0x02 => Some(8 * 1024),
+        0x03 => Some(32 * 1024),
+        0x04 => Some(128 * 1024),
+        0x05 => Some(64 * 1024),
+        _ => None,
+    }
+}
+
+// ROM images with a header that passes validation, for tests all over the crate
+#[cfg(test)]
+pub mod test_rom {
+    use super::*;
+
+    // Where `with_program` places its code, right after the header
+    pub const PROGRAM_START: u16 = 0x0150;
+    const JP_OPCODE: u8 = 0xC3;
+    const MIN_ROM_SIZE: usize = 32 * 1024;
+
+    // Blank image of `rom_size` bytes, which has to be a power of two from 32 KiB up
+    pub fn build(cartridge_type: u8, rom_size: usize, ram_size: u8) -> Vec<u8> {
+        let mut rom = vec![0; rom_size];
+        rom[LOGO_START..=LOGO_END].copy_from_slice(&NINTENDO_LOGO);
+        rom[CARTRIDGE_TYPE_ADDRESS] = cartridge_type;
+        rom[ROM_SIZE_ADDRESS] = (rom_size / MIN_ROM_SIZE).trailing_zeros() as u8;
+        rom[RAM_SIZE_ADDRESS] = ram_size;
+        fix_checksum(&mut rom);
+        rom
+    }
+
+    // 32 KiB ROM-only image whose entry point jumps straight to `program`
+    pub fn with_program(program: &[u8]) -> Vec<u8> {
+        let mut rom = build(0x00, MIN_ROM_SIZE, 0x00);
+        let [low, high] = PROGRAM_START.to_le_bytes();
+        rom[0x0100..0x0104].copy_from_slice(&[0x00, JP_OPCODE, low, high]);
+        let start = PROGRAM_START as usize;
+        rom[start..start + program.len()].copy_from_slice(program);
+        rom
+    }
+
+    // For tests that edit the header after building it
+    pub fn fix_checksum(rom: &mut [u8]) {
+        rom[HEADER_CHECKSUM_ADDRESS] = header_checksum(rom);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // 32 KiB MBC1 ROM titled "TEST" with a correct header checksum
+    fn valid_rom() -> Vec<u8> {
+        let mut rom = test_rom::build(0x01, 32 * 1024, 0x00);
+        rom[TITLE_START..TITLE_START + 4].copy_from_slice(b"TEST");
+        test_rom::fix_checksum(&mut rom);
+        rom
+    }
+
+    #[test]
+    fn parses_valid_header() {
+        let header = CartridgeHeader::parse(&valid_rom()).unwrap();
+        assert_eq!(header.title, "TEST");
+        assert_eq!(header.cartridge_type, CartridgeType::Mbc1);
+        assert_eq!(header.rom_size, 32 * 1024);
+        assert_eq!(header.ram_size, 0);
+    }
+
+    #[test]
+    fn rejects_header_checksum_mismatch() {
+        let mut rom = valid_rom();
+        rom[TITLE_START] = b'X';
+        let expected = rom[HEADER_CHECKSUM_ADDRESS];
+        let computed = header_checksum(&rom);
+        assert!(matches!(
+            CartridgeHeader::parse(&rom),
+            Err(CartridgeError::HeaderChecksumMismatch { expected: e, computed: c })
+                if e == expected && c == computed
+        ));
+    }
+
+    #[test]
+    fn rejects_corrupt_logo() {
+        let mut rom = valid_rom();
+        rom[LOGO_START + 10] ^= 0xFF;
+        assert!(matches!(
+            CartridgeHeader::parse(&rom),
+            Err(CartridgeError::InvalidLogo)
+        ));
+    }
+
+    #[test]
+    fn rejects_truncated_rom() {
+        assert!(matches!(
+            CartridgeHeader::parse(&valid_rom()[..0x0100]),
+            Err(CartridgeError::TooSmall(0x0100))
+        ));
+    }
+}
@@ -0,0 +1,167 @@
+use super::{RAM_BANK_SIZE, ROM_BANK_SIZE};
+
+const RAM_ENABLE_END: u16 = 0x1FFF;
+const ROM_BANK_NUMBER_END: u16 = 0x3FFF;
+const RAM_BANK_NUMBER_END: u16 = 0x5FFF;
+const BANKING_MODE_END: u16 = 0x7FFF;
+
+const SWITCHABLE_ROM_START: u16 = 0x4000;
+
+pub struct Mbc1 {
+    ram_enabled: bool,
+    // BANK1: the lower 5 bits of the ROM bank number
+    rom_bank: u8,
+    // BANK2: 2 bits used either as the upper ROM bank bits or as the RAM bank
+    ram_bank: u8,
+    // Mode 1 lets BANK2 also affect 0x0000-0x3FFF and the RAM area
+    advanced_banking: bool,
+    // MBC1M multicarts leave BANK1 bit 4 unconnected, so BANK2 lands on ROM address bit 18 instead of 19
+    multicart: bool,
+    rom_bank_count: usize,
+    ram_bank_count: usize,
+}
+
+impl Mbc1 {
+    pub fn new(rom_bank_count: usize, ram_bank_count: usize, multicart: bool) -> Mbc1 {
+        Mbc1 {
+            ram_enabled: false,
+            rom_bank: 1,
+            ram_bank: 0,
+            advanced_banking: false,
+            multicart,
+            rom_bank_count,
+            ram_bank_count,
+        }
+    }
+
+    pub fn read_rom(&self, rom: &[u8], address: u16) -> u8 {
+        let bank = if address < SWITCHABLE_ROM_START {
+            if self.advanced_banking {
+                self.upper_bank_bits()
+            } else {
+                0
+            }
+        } else {
+            self.upper_bank_bits() | self.lower_bank_bits()
+        };
+        let bank = bank % self.rom_bank_count;
+        let offset = bank * ROM_BANK_SIZE + (address as usize % ROM_BANK_SIZE);
+        rom.get(offset).copied().unwrap_or(0xFF)
+    }
+
+    pub fn write_register(&mut self, address: u16, value: u8) {
+        match address {
+            0x0000..=RAM_ENABLE_END => self.ram_enabled = value & 0x0F == 0x0A,
+            // The zero check looks at all 5 bits, so writing 0x20 selects bank 0x21 and not 0x20
+            0x2000..=ROM_BANK_NUMBER_END => {
+                self.rom_bank = match value & 0x1F {
+                    0 => 1,
+                    bank => bank,
+                }
+            }
+            0x4000..=RAM_BANK_NUMBER_END => self.ram_bank = value & 0x03,
+            0x6000..=BANKING_MODE_END => self.advanced_banking = value & 0x01 == 0x01,
+            _ => {}
+        }
+    }
+
+    pub fn read_ram(&self, ram: &[u8], address: u16) -> u8 {
+        if !self.ram_enabled {
+            return 0xFF;
+        }
+        ram.get(self.ram_offset(address)).copied().unwrap_or(0xFF)
+    }
+
+    pub fn write_ram(&mut self, ram: &mut [u8], address: u16, value: u8) -> bool {
+        if !self.ram_enabled {
+            return false;
+        }
+        match ram.get_mut(self.ram_offset(address)) {
+            Some(byte) => {
+                *byte = value;
+                true
+            }
+            None => false,
+        }
+    }
+
+    fn lower_bank_bits(&self) -> usize {
+        if self.multicart {
+            (self.rom_bank & 0x0F) as usize
+        } else {
+            self.rom_bank as usize
+        }
+    }
+
+    fn upper_bank_bits(&self) -> usize {
+        if self.multicart {
+            (self.ram_bank as usize) << 4
+        } else {
+            (self.ram_bank as usize) << 5
+        }
+    }
+
+    fn ram_offset(&self, address: u16) -> usize {
+        let bank = if self.advanced_banking && self.ram_bank_count > 0 {
+            self.ram_bank as usize % self.ram_bank_count
+        } else {
+            0
+        };
+        bank * RAM_BANK_SIZE + (address as usize % RAM_BANK_SIZE)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Every bank starts with its own number
+    fn banked_rom(bank_count: usize) -> Vec<u8> {
+        let mut rom = vec![0; bank_count * ROM_BANK_SIZE];
+        for (bank, contents) in rom.chunks_mut(ROM_BANK_SIZE).enumerate() {
+            contents[0] = bank as u8;
+        }
+        rom
+    }
+
+    #[test]
+    fn bank_0_selects_bank_1() {
+        let rom = banked_rom(4);
+        let mut mbc = Mbc1::new(4, 0, false);
+        mbc.write_register(0x2000, 0x02);
+        assert_eq!(mbc.read_rom(&rom, 0x4000), 2);
+        mbc.write_register(0x2000, 0x00);
+        assert_eq!(mbc.read_rom(&rom, 0x4000), 1);
+    }
+
+    #[test]
+    fn bank_0x20_selects_bank_0x21() {
+        let rom = banked_rom(64);
+        let mut mbc = Mbc1::new(64, 0, false);
+        mbc.write_register(0x4000, 0x01);
+        mbc.write_register(0x2000, 0x00);
+        assert_eq!(mbc.read_rom(&rom, 0x4000), 0x21);
+        // Only the upper bits are in the way, so 0x22 maps as written
+        mbc.write_register(0x2000, 0x02);
+        assert_eq!(mbc.read_rom(&rom, 0x4000), 0x22);
+        // Mode 1 also maps BANK2 into the lower area
+        assert_eq!(mbc.read_rom(&rom, 0x0000), 0x00);
+        mbc.write_register(0x6000, 0x01);
+        assert_eq!(mbc.read_rom(&rom, 0x0000), 0x20);
+    }
+
+    #[test]
+    fn multicart_shifts_bank2_down_one_bit() {
+        let rom = banked_rom(64);
+        let mut mbc = Mbc1::new(64, 0, true);
+        mbc.write_register(0x4000, 0x02);
+        // Bit 4 of BANK1 isn't wired up, so 0x12 only contributes 0x02
+        mbc.write_register(0x2000, 0x12);
+        assert_eq!(mbc.read_rom(&rom, 0x4000), 0x22);
+        mbc.write_register(0x4000, 0x03);
+        assert_eq!(mbc.read_rom(&rom, 0x4000), 0x32);
+        // Each game's bank 0 is picked the same way in mode 1
+        mbc.write_register(0x6000, 0x01);
+        assert_eq!(mbc.read_rom(&rom, 0x0000), 0x30);
+    }
+}
@@ -0,0 +1,117 @@
+use super::ROM_BANK_SIZE;
+
+// 512 half-bytes of RAM are built into the controller itself
+pub const MBC2_RAM_SIZE: usize = 512;
+
+const REGISTER_END: u16 = 0x3FFF;
+// Address bit 8 decides which register a write in 0x0000-0x3FFF lands in
+const REGISTER_SELECT_BIT: u16 = 0x0100;
+
+const SWITCHABLE_ROM_START: u16 = 0x4000;
+
+pub struct Mbc2 {
+    ram_enabled: bool,
+    rom_bank: u8,
+    rom_bank_count: usize,
+}
+
+impl Mbc2 {
+    pub fn new(rom_bank_count: usize) -> Mbc2 {
+        Mbc2 {
+            ram_enabled: false,
+            rom_bank: 1,
+            rom_bank_count,
+        }
+    }
+
+    pub fn read_rom(&self, rom: &[u8], address: u16) -> u8 {
+        let bank = if address < SWITCHABLE_ROM_START {
+            0
+        } else {
+            self.rom_bank as usize % self.rom_bank_count
+        };
+        let offset = bank * ROM_BANK_SIZE + (address as usize % ROM_BANK_SIZE);
+        rom.get(offset).copied().unwrap_or(0xFF)
+    }
+
+    pub fn write_register(&mut self, address: u16, value: u8) {
+        if address > REGISTER_END {
+            return;
+        }
+        if address & REGISTER_SELECT_BIT == 0 {
+            self.ram_enabled = value & 0x0F == 0x0A;
+        } else {
+            self.rom_bank = match value & 0x0F {
+                0 => 1,
+                bank => bank,
+            };
+        }
+    }
+
+    // Only the low nibble exists; the data lines for the upper one float high
+    pub fn read_ram(&self, ram: &[u8], address: u16) -> u8 {
+        if !self.ram_enabled {
+            return 0xFF;
+        }
+        ram.get(ram_offset(address))
+            .map_or(0xFF, |&nibble| nibble | 0xF0)
+    }
+
+    pub fn write_ram(&mut self, ram: &mut [u8], address: u16, value: u8) -> bool {
+        if !self.ram_enabled {
+            return false;
+        }
+        match ram.get_mut(ram_offset(address)) {
+            Some(nibble) => {
+                *nibble = value & 0x0F;
+                true
+            }
+            None => false,
+        }
+    }
+}
+
+// Only 9 address bits are decoded, so the 512 cells repeat across 0xA000-0xBFFF
+fn ram_offset(address: u16) -> usize {
+    address as usize % MBC2_RAM_SIZE
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn ram_stores_nibbles_and_reads_the_upper_half_high() {
+        let mut ram = vec![0; MBC2_RAM_SIZE];
+        let mut mbc = Mbc2::new(2);
+        assert!(!mbc.write_ram(&mut ram, 0xA000, 0x12));
+        mbc.write_register(0x0000, 0x0A);
+        assert!(mbc.write_ram(&mut ram, 0xA000, 0x5C));
+        assert_eq!(ram[0], 0x0C);
+        assert_eq!(mbc.read_ram(&ram, 0xA000), 0xFC);
+        // The 512 cells repeat through the whole area
+        assert_eq!(mbc.read_ram(&ram, 0xA200), 0xFC);
+        assert_eq!(mbc.read_ram(&ram, 0xBE00), 0xFC);
+    }
+
+    #[test]
+    fn address_bit_8_selects_the_register() {
+        let mut rom = vec![0; 4 * ROM_BANK_SIZE];
+        for (bank, contents) in rom.chunks_mut(ROM_BANK_SIZE).enumerate() {
+            contents[0] = bank as u8;
+        }
+        let mut mbc = Mbc2::new(4);
+        // Bit 8 clear: RAM enable, even in the upper half of the range
+        mbc.write_register(0x2000, 0x0A);
+        assert_eq!(mbc.read_rom(&rom, 0x4000), 1);
+        assert!(mbc.ram_enabled);
+        // Bit 8 set: ROM bank, even in the lower half
+        mbc.write_register(0x0100, 0x03);
+        assert_eq!(mbc.read_rom(&rom, 0x4000), 3);
+        assert!(mbc.ram_enabled);
+        mbc.write_register(0x2100, 0x00);
+        assert_eq!(mbc.read_rom(&rom, 0x4000), 1);
+        mbc.write_register(0x3E00, 0x00);
+        assert!(!mbc.ram_enabled);
+    }
+}
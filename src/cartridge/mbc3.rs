@@ -0,0 +1,104 @@
+use super::rtc::{RealTimeClock, RtcMode, DAY_HIGH_REGISTER, SECONDS_REGISTER};
+use super::{RAM_BANK_SIZE, ROM_BANK_SIZE};
+
+const RAM_ENABLE_END: u16 = 0x1FFF;
+const ROM_BANK_NUMBER_END: u16 = 0x3FFF;
+const RAM_BANK_NUMBER_END: u16 = 0x5FFF;
+const LATCH_CLOCK_END: u16 = 0x7FFF;
+
+const SWITCHABLE_ROM_START: u16 = 0x4000;
+
+pub struct Mbc3 {
+    // Gates both the RAM banks and the RTC registers
+    ram_enabled: bool,
+    rom_bank: u8,
+    // 0x00-0x03 select a RAM bank, 0x08-0x0C map an RTC register instead
+    ram_bank: u8,
+    rom_bank_count: usize,
+    ram_bank_count: usize,
+    pub rtc: Option<RealTimeClock>,
+}
+
+impl Mbc3 {
+    pub fn new(rom_bank_count: usize, ram_bank_count: usize, rtc: Option<RtcMode>) -> Mbc3 {
+        Mbc3 {
+            ram_enabled: false,
+            rom_bank: 1,
+            ram_bank: 0,
+            rom_bank_count,
+            ram_bank_count,
+            rtc: rtc.map(RealTimeClock::new),
+        }
+    }
+
+    pub fn read_rom(&self, rom: &[u8], address: u16) -> u8 {
+        let bank = if address < SWITCHABLE_ROM_START {
+            0
+        } else {
+            self.rom_bank as usize % self.rom_bank_count
+        };
+        let offset = bank * ROM_BANK_SIZE + (address as usize % ROM_BANK_SIZE);
+        rom.get(offset).copied().unwrap_or(0xFF)
+    }
+
+    pub fn write_register(&mut self, address: u16, value: u8) {
+        match address {
+            0x0000..=RAM_ENABLE_END => self.ram_enabled = value & 0x0F == 0x0A,
+            0x2000..=ROM_BANK_NUMBER_END => {
+                self.rom_bank = match value & 0x7F {
+                    0 => 1,
+                    bank => bank,
+                }
+            }
+            0x4000..=RAM_BANK_NUMBER_END => self.ram_bank = value,
+            0x6000..=LATCH_CLOCK_END => {
+                if let Some(rtc) = &mut self.rtc {
+                    rtc.write_latch(value);
+                }
+            }
+            _ => {}
+        }
+    }
+
+    pub fn read_ram(&self, ram: &[u8], address: u16) -> u8 {
+        if !self.ram_enabled {
+            return 0xFF;
+        }
+        match (self.ram_bank, &self.rtc) {
+            (SECONDS_REGISTER..=DAY_HIGH_REGISTER, Some(rtc)) => rtc.read(self.ram_bank),
+            (0x00..=0x03, _) => ram.get(self.ram_offset(address)).copied().unwrap_or(0xFF),
+            _ => 0xFF,
+        }
+    }
+
+    // Clock writes count as stored too, since the clock is part of the save
+    pub fn write_ram(&mut self, ram: &mut [u8], address: u16, value: u8) -> bool {
+        if !self.ram_enabled {
+            return false;
+        }
+        let offset = self.ram_offset(address);
+        match (self.ram_bank, &mut self.rtc) {
+            (SECONDS_REGISTER..=DAY_HIGH_REGISTER, Some(rtc)) => {
+                rtc.write(self.ram_bank, value);
+                true
+            }
+            (0x00..=0x03, _) => match ram.get_mut(offset) {
+                Some(byte) => {
+                    *byte = value;
+                    true
+                }
+                None => false,
+            },
+            _ => false,
+        }
+    }
+
+    fn ram_offset(&self, address: u16) -> usize {
+        let bank = if self.ram_bank_count > 0 {
+            self.ram_bank as usize % self.ram_bank_count
+        } else {
+            0
+        };
+        bank * RAM_BANK_SIZE + (address as usize % RAM_BANK_SIZE)
+    }
+}
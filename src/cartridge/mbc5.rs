@@ -0,0 +1,165 @@
+use super::{RAM_BANK_SIZE, ROM_BANK_SIZE};
+
+const RAM_ENABLE_END: u16 = 0x1FFF;
+const ROM_BANK_LOW_END: u16 = 0x2FFF;
+const ROM_BANK_HIGH_END: u16 = 0x3FFF;
+const RAM_BANK_NUMBER_END: u16 = 0x5FFF;
+
+const SWITCHABLE_ROM_START: u16 = 0x4000;
+
+// On rumble cartridges bit 3 of the RAM bank register drives the motor instead of a RAM address line
+const RUMBLE_MOTOR_BIT: u8 = 0x08;
+
+pub struct Mbc5 {
+    ram_enabled: bool,
+    // 9-bit bank number; unlike MBC1 and MBC3, bank 0 can be mapped into 0x4000-0x7FFF
+    rom_bank: u16,
+    ram_bank: u8,
+    rumble: bool,
+    motor_on: bool,
+    // Set when the motor changes state, until the change is collected with `take_rumble_change`
+    motor_changed: bool,
+    rom_bank_count: usize,
+    ram_bank_count: usize,
+}
+
+impl Mbc5 {
+    pub fn new(rom_bank_count: usize, ram_bank_count: usize, rumble: bool) -> Mbc5 {
+        Mbc5 {
+            ram_enabled: false,
+            rom_bank: 1,
+            ram_bank: 0,
+            rumble,
+            motor_on: false,
+            motor_changed: false,
+            rom_bank_count,
+            ram_bank_count,
+        }
+    }
+
+    pub fn read_rom(&self, rom: &[u8], address: u16) -> u8 {
+        let bank = if address < SWITCHABLE_ROM_START {
+            0
+        } else {
+            self.rom_bank as usize % self.rom_bank_count
+        };
+        let offset = bank * ROM_BANK_SIZE + (address as usize % ROM_BANK_SIZE);
+        rom.get(offset).copied().unwrap_or(0xFF)
+    }
+
+    pub fn write_register(&mut self, address: u16, value: u8) {
+        match address {
+            // MBC5 compares the whole byte, not just the lower nibble
+            0x0000..=RAM_ENABLE_END => self.ram_enabled = value == 0x0A,
+            0x2000..=ROM_BANK_LOW_END => self.rom_bank = (self.rom_bank & 0x100) | value as u16,
+            0x3000..=ROM_BANK_HIGH_END => {
+                self.rom_bank = (self.rom_bank & 0xFF) | ((value as u16 & 0x01) << 8)
+            }
+            0x4000..=RAM_BANK_NUMBER_END => {
+                if self.rumble {
+                    let motor_on = value & RUMBLE_MOTOR_BIT != 0;
+                    if motor_on != self.motor_on {
+                        self.motor_on = motor_on;
+                        self.motor_changed = true;
+                    }
+                    self.ram_bank = value & 0x07;
+                } else {
+                    self.ram_bank = value & 0x0F;
+                }
+            }
+            _ => {}
+        }
+    }
+
+    pub fn read_ram(&self, ram: &[u8], address: u16) -> u8 {
+        if !self.ram_enabled {
+            return 0xFF;
+        }
+        ram.get(self.ram_offset(address)).copied().unwrap_or(0xFF)
+    }
+
+    pub fn write_ram(&mut self, ram: &mut [u8], address: u16, value: u8) -> bool {
+        if !self.ram_enabled {
+            return false;
+        }
+        match ram.get_mut(self.ram_offset(address)) {
+            Some(byte) => {
+                *byte = value;
+                true
+            }
+            None => false,
+        }
+    }
+
+    pub fn take_rumble_change(&mut self) -> Option<bool> {
+        if self.motor_changed {
+            self.motor_changed = false;
+            Some(self.motor_on)
+        } else {
+            None
+        }
+    }
+
+    fn ram_offset(&self, address: u16) -> usize {
+        let bank = if self.ram_bank_count > 0 {
+            self.ram_bank as usize % self.ram_bank_count
+        } else {
+            0
+        };
+        bank * RAM_BANK_SIZE + (address as usize % RAM_BANK_SIZE)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Every bank starts with its 9-bit number, low byte first
+    fn banked_rom(bank_count: usize) -> Vec<u8> {
+        let mut rom = vec![0; bank_count * ROM_BANK_SIZE];
+        for (bank, contents) in rom.chunks_mut(ROM_BANK_SIZE).enumerate() {
+            contents[0] = bank as u8;
+            contents[1] = (bank >> 8) as u8;
+        }
+        rom
+    }
+
+    fn switchable_bank(mbc: &Mbc5, rom: &[u8]) -> u16 {
+        u16::from_le_bytes([mbc.read_rom(rom, 0x4000), mbc.read_rom(rom, 0x4001)])
+    }
+
+    #[test]
+    fn rom_bank_number_has_9_bits_and_0_is_selectable() {
+        let rom = banked_rom(512);
+        let mut mbc = Mbc5::new(512, 0, false);
+        mbc.write_register(0x2000, 0x00);
+        assert_eq!(switchable_bank(&mbc, &rom), 0);
+        mbc.write_register(0x3000, 0x01);
+        assert_eq!(switchable_bank(&mbc, &rom), 0x100);
+        mbc.write_register(0x2000, 0x23);
+        assert_eq!(switchable_bank(&mbc, &rom), 0x123);
+        mbc.write_register(0x3000, 0x00);
+        assert_eq!(switchable_bank(&mbc, &rom), 0x023);
+    }
+
+    #[test]
+    fn rumble_bit_drives_the_motor_instead_of_the_ram_bank() {
+        let mut ram = vec![0; 16 * RAM_BANK_SIZE];
+        let mut mbc = Mbc5::new(2, 16, true);
+        mbc.write_register(0x0000, 0x0A);
+        mbc.write_register(0x4000, 0x01);
+        assert!(mbc.write_ram(&mut ram, 0xA000, 0x11));
+        assert_eq!(mbc.take_rumble_change(), None);
+
+        // Bank 9 on a plain MBC5, but bank 1 with the motor running here
+        mbc.write_register(0x4000, 0x09);
+        assert_eq!(mbc.take_rumble_change(), Some(true));
+        assert_eq!(mbc.take_rumble_change(), None);
+        assert_eq!(mbc.read_ram(&ram, 0xA000), 0x11);
+        assert!(mbc.write_ram(&mut ram, 0xA001, 0x22));
+        assert_eq!(ram[RAM_BANK_SIZE + 1], 0x22);
+
+        mbc.write_register(0x4000, 0x01);
+        assert_eq!(mbc.take_rumble_change(), Some(false));
+    }
+}
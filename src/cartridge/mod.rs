@@ -0,0 +1,385 @@
+pub mod header;
+pub mod mbc1;
+pub mod mbc2;
+pub mod mbc3;
+pub mod mbc5;
+pub mod rtc;
+pub mod save;
+
+use std::fmt;
+use std::fs;
+use std::io;
+use std::path::Path;
+use std::time::Duration;
+
+use header::{CartridgeHeader, CartridgeType};
+use mbc1::Mbc1;
+use mbc2::{Mbc2, MBC2_RAM_SIZE};
+use mbc3::Mbc3;
+use mbc5::Mbc5;
+use rtc::RtcMode;
+use save::SaveFile;
+
+pub const ROM_BANK_SIZE: usize = 0x4000;
+pub const RAM_BANK_SIZE: usize = 0x2000;
+
+const EXTERNAL_RAM_START: u16 = 0xA000;
+
+// MBC1M multicarts are all 8 Mbit and carry a second bootable header in bank 0x10
+const MBC1_MULTICART_ROM_SIZE: usize = 1024 * 1024;
+const MBC1_MULTICART_GAME_BANK: usize = 0x10;
+
+pub enum MemoryBankController {
+    RomOnly,
+    Mbc1(Mbc1),
+    Mbc2(Mbc2),
+    Mbc3(Mbc3),
+    Mbc5(Mbc5),
+}
+
+impl MemoryBankController {
+    fn for_cartridge(
+        header: &CartridgeHeader,
+        rom: &[u8],
+    ) -> Result<MemoryBankController, CartridgeError> {
+        let rom_bank_count = (rom.len() / ROM_BANK_SIZE).max(1);
+        let ram_bank_count = header.ram_size / RAM_BANK_SIZE;
+
+        match header.cartridge_type {
+            CartridgeType::RomOnly | CartridgeType::RomRam | CartridgeType::RomRamBattery => {
+                Ok(MemoryBankController::RomOnly)
+            }
+            CartridgeType::Mbc1 | CartridgeType::Mbc1Ram | CartridgeType::Mbc1RamBattery => {
+                Ok(MemoryBankController::Mbc1(Mbc1::new(
+                    rom_bank_count,
+                    ram_bank_count,
+                    is_mbc1_multicart(rom),
+                )))
+            }
+            CartridgeType::Mbc2 | CartridgeType::Mbc2Battery => {
+                Ok(MemoryBankController::Mbc2(Mbc2::new(rom_bank_count)))
+            }
+            CartridgeType::Mbc3
+            | CartridgeType::Mbc3Ram
+            | CartridgeType::Mbc3RamBattery
+            | CartridgeType::Mbc3TimerBattery
+            | CartridgeType::Mbc3TimerRamBattery => Ok(MemoryBankController::Mbc3(Mbc3::new(
+                rom_bank_count,
+                ram_bank_count,
+                header
+                    .cartridge_type
+                    .has_timer()
+                    .then_some(RtcMode::WallTime),
+            ))),
+            CartridgeType::Mbc5
+            | CartridgeType::Mbc5Ram
+            | CartridgeType::Mbc5RamBattery
+            | CartridgeType::Mbc5Rumble
+            | CartridgeType::Mbc5RumbleRam
+            | CartridgeType::Mbc5RumbleRamBattery => Ok(MemoryBankController::Mbc5(Mbc5::new(
+                rom_bank_count,
+                ram_bank_count,
+                header.cartridge_type.has_rumble(),
+            ))),
+            cartridge_type => Err(CartridgeError::UnsupportedController(cartridge_type)),
+        }
+    }
+}
+
+pub struct Cartridge {
+    pub header: CartridgeHeader,
+    rom: Vec<u8>,
+    ram: Vec<u8>,
+    mbc: MemoryBankController,
+    save_file: Option<SaveFile>,
+    // RAM has been written since it was last persisted
+    dirty: bool,
+}
+
+impl Cartridge {
+    // Battery-backed cartridges pick up the `.sav` file next to the ROM, if there is one
+    pub fn from_file<P: AsRef<Path>>(path: P) -> Result<Cartridge, CartridgeError> {
+        let rom = fs::read(&path)?;
+        let mut cartridge = Cartridge::from_bytes(rom)?;
+        if cartridge.header.cartridge_type.has_battery() {
+            cartridge.attach_save_file(SaveFile::for_rom(&path))?;
+        }
+        Ok(cartridge)
+    }
+
+    pub fn from_bytes(rom: Vec<u8>) -> Result<Cartridge, CartridgeError> {
+        let header = CartridgeHeader::parse(&rom)?;
+
+        if rom.len() < header.rom_size {
+            return Err(CartridgeError::RomSizeMismatch {
+                expected: header.rom_size,
+                actual: rom.len(),
+            });
+        }
+
+        let mbc = MemoryBankController::for_cartridge(&header, &rom)?;
+        // MBC2 carts declare no RAM in the header since it lives inside the controller
+        let ram_size = match mbc {
+            MemoryBankController::Mbc2(_) => MBC2_RAM_SIZE,
+            _ => header.ram_size,
+        };
+        let ram = vec![0; ram_size];
+        Ok(Cartridge {
+            header,
+            rom,
+            ram,
+            mbc,
+            save_file: None,
+            dirty: false,
+        })
+    }
+
+    // Loads the save file's contents, if it exists, and persists RAM back to it from now on
+    pub fn attach_save_file(&mut self, save_file: SaveFile) -> io::Result<()> {
+        if let Some(data) = save_file.read()? {
+            self.load_save_data(&data);
+        }
+        self.save_file = Some(save_file);
+        self.dirty = false;
+        Ok(())
+    }
+
+    pub fn set_save_interval(&mut self, interval: Duration) {
+        if let Some(save_file) = &mut self.save_file {
+            save_file.set_interval(interval);
+        }
+    }
+
+    // 0x0000 - 0x7FFF
+    pub fn read_rom(&self, address: u16) -> u8 {
+        match &self.mbc {
+            MemoryBankController::RomOnly => {
+                self.rom.get(address as usize).copied().unwrap_or(0xFF)
+            }
+            MemoryBankController::Mbc1(mbc1) => mbc1.read_rom(&self.rom, address),
+            MemoryBankController::Mbc2(mbc2) => mbc2.read_rom(&self.rom, address),
+            MemoryBankController::Mbc3(mbc3) => mbc3.read_rom(&self.rom, address),
+            MemoryBankController::Mbc5(mbc5) => mbc5.read_rom(&self.rom, address),
+        }
+    }
+
+    // Writes into the ROM area never change ROM contents; they are how
+    // bank controllers receive commands. A plain ROM has none, so they vanish.
+    pub fn write_rom(&mut self, address: u16, value: u8) {
+        match &mut self.mbc {
+            MemoryBankController::RomOnly => {}
+            MemoryBankController::Mbc1(mbc1) => mbc1.write_register(address, value),
+            MemoryBankController::Mbc2(mbc2) => mbc2.write_register(address, value),
+            MemoryBankController::Mbc3(mbc3) => mbc3.write_register(address, value),
+            MemoryBankController::Mbc5(mbc5) => mbc5.write_register(address, value),
+        }
+    }
+
+    // 0xA000 - 0xBFFF
+    pub fn read_ram(&self, address: u16) -> u8 {
+        match &self.mbc {
+            MemoryBankController::RomOnly => self
+                .ram
+                .get((address - EXTERNAL_RAM_START) as usize)
+                .copied()
+                .unwrap_or(0xFF),
+            MemoryBankController::Mbc1(mbc1) => mbc1.read_ram(&self.ram, address),
+            MemoryBankController::Mbc2(mbc2) => mbc2.read_ram(&self.ram, address),
+            MemoryBankController::Mbc3(mbc3) => mbc3.read_ram(&self.ram, address),
+            MemoryBankController::Mbc5(mbc5) => mbc5.read_ram(&self.ram, address),
+        }
+    }
+
+    // Only writes that reached RAM make the save dirty; games poking RAM while it's
+    // disabled would otherwise trigger pointless rewrites
+    pub fn write_ram(&mut self, address: u16, value: u8) {
+        let stored = match &mut self.mbc {
+            MemoryBankController::RomOnly => {
+                match self.ram.get_mut((address - EXTERNAL_RAM_START) as usize) {
+                    Some(byte) => {
+                        *byte = value;
+                        true
+                    }
+                    None => false,
+                }
+            }
+            MemoryBankController::Mbc1(mbc1) => mbc1.write_ram(&mut self.ram, address, value),
+            MemoryBankController::Mbc2(mbc2) => mbc2.write_ram(&mut self.ram, address, value),
+            MemoryBankController::Mbc3(mbc3) => mbc3.write_ram(&mut self.ram, address, value),
+            MemoryBankController::Mbc5(mbc5) => mbc5.write_ram(&mut self.ram, address, value),
+        };
+        self.dirty |= stored;
+    }
+
+    // Advances cartridge hardware that counts CPU cycles, i.e. the MBC3 clock in emulated mode
+    pub fn tick(&mut self, cycles: u32) {
+        if let MemoryBankController::Mbc3(Mbc3 { rtc: Some(rtc), .. }) = &mut self.mbc {
+            rtc.tick(cycles);
+        }
+    }
+
+    // Reports the new motor state if a rumble cartridge switched its motor since the last call
+    pub fn take_rumble_change(&mut self) -> Option<bool> {
+        match &mut self.mbc {
+            MemoryBankController::Mbc5(mbc5) => mbc5.take_rumble_change(),
+            _ => None,
+        }
+    }
+
+    pub fn set_rtc_mode(&mut self, mode: RtcMode) {
+        if let MemoryBankController::Mbc3(Mbc3 { rtc: Some(rtc), .. }) = &mut self.mbc {
+            rtc.set_mode(mode);
+        }
+    }
+
+    // Called regularly while running so progress survives a crash; only writes
+    // when RAM changed and the save interval has passed since the last write
+    pub fn flush_save_if_due(&mut self) -> io::Result<()> {
+        match &self.save_file {
+            Some(save_file) if self.dirty && save_file.is_due() => self.flush_save(),
+            _ => Ok(()),
+        }
+    }
+
+    pub fn flush_save(&mut self) -> io::Result<()> {
+        if self.save_file.is_none() {
+            return Ok(());
+        }
+        let data = self.save_data();
+        if let Some(save_file) = &mut self.save_file {
+            save_file.write(&data)?;
+        }
+        self.dirty = false;
+        Ok(())
+    }
+
+    // Persists the save before the host shuts down. The RTC trailer is rewritten even
+    // without RAM changes so clock time that passed in emulated mode isn't lost.
+    pub fn flush(&mut self) -> io::Result<()> {
+        if !self.dirty && !self.header.cartridge_type.has_timer() {
+            return Ok(());
+        }
+        self.flush_save()
+    }
+
+    // Contents of a battery save: the external RAM, followed by the RTC state for MBC3 timer carts
+    pub fn save_data(&mut self) -> Vec<u8> {
+        let mut data = self.ram.clone();
+        if let MemoryBankController::Mbc3(Mbc3 { rtc: Some(rtc), .. }) = &mut self.mbc {
+            data.extend_from_slice(&rtc.save_trailer());
+        }
+        data
+    }
+
+    pub fn load_save_data(&mut self, data: &[u8]) {
+        let ram_length = self.ram.len().min(data.len());
+        self.ram[..ram_length].copy_from_slice(&data[..ram_length]);
+        if let MemoryBankController::Mbc3(Mbc3 { rtc: Some(rtc), .. }) = &mut self.mbc {
+            rtc.load_save_trailer(&data[ram_length..]);
+        }
+    }
+}
+
+// A game saved since the last periodic write still has its RAM on disk afterwards
+impl Drop for Cartridge {
+    fn drop(&mut self) {
+        let _ = self.flush();
+    }
+}
+
+// The header only ever says "MBC1", so multicarts are told apart by looking
+// for the logo of a second game where the first one ends
+fn is_mbc1_multicart(rom: &[u8]) -> bool {
+    rom.len() == MBC1_MULTICART_ROM_SIZE
+        && header::logo_matches(rom, MBC1_MULTICART_GAME_BANK * ROM_BANK_SIZE)
+}
+
+#[derive(Debug)]
+pub enum CartridgeError {
+    Io(io::Error),
+    TooSmall(usize),
+    InvalidLogo,
+    HeaderChecksumMismatch { expected: u8, computed: u8 },
+    UnsupportedCartridgeType(u8),
+    UnsupportedController(CartridgeType),
+    InvalidRomSize(u8),
+    InvalidRamSize(u8),
+    RomSizeMismatch { expected: usize, actual: usize },
+}
+
+impl fmt::Display for CartridgeError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            CartridgeError::Io(error) => write!(f, "could not read ROM file: {}", error),
+            CartridgeError::TooSmall(size) => write!(
+                f,
+                "ROM is {} bytes, too small to contain a cartridge header",
+                size
+            ),
+            CartridgeError::InvalidLogo => write!(f, "Nintendo logo in the header is corrupt"),
+            CartridgeError::HeaderChecksumMismatch { expected, computed } => write!(
+                f,
+                "header checksum mismatch: header says 0x{:02x}, computed 0x{:02x}",
+                expected, computed
+            ),
+            CartridgeError::UnsupportedCartridgeType(byte) => {
+                write!(f, "unsupported cartridge type 0x{:02x}", byte)
+            }
+            CartridgeError::UnsupportedController(cartridge_type) => {
+                write!(f, "{:?} cartridges are not supported yet", cartridge_type)
+            }
+            CartridgeError::InvalidRomSize(code) => {
+                write!(f, "invalid ROM size code 0x{:02x}", code)
+            }
+            CartridgeError::InvalidRamSize(code) => {
+                write!(f, "invalid RAM size code 0x{:02x}", code)
+            }
+            CartridgeError::RomSizeMismatch { expected, actual } => write!(
+                f,
+                "header declares a {} byte ROM but the file is {} bytes",
+                expected, actual
+            ),
+        }
+    }
+}
+
+impl std::error::Error for CartridgeError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            CartridgeError::Io(error) => Some(error),
+            _ => None,
+        }
+    }
+}
+
+impl From<io::Error> for CartridgeError {
+    fn from(error: io::Error) -> Self {
+        CartridgeError::Io(error)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const MBC1_RAM_BATTERY: u8 = 0x03;
+    const RAM_SIZE_8_KIB: u8 = 0x02;
+
+    fn mbc1_cartridge() -> Cartridge {
+        let rom = header::test_rom::build(MBC1_RAM_BATTERY, 32 * 1024, RAM_SIZE_8_KIB);
+        Cartridge::from_bytes(rom).unwrap()
+    }
+
+    #[test]
+    fn writes_to_disabled_ram_are_not_saved() {
+        let mut cartridge = mbc1_cartridge();
+        cartridge.write_ram(0xA000, 0x42);
+        assert!(!cartridge.dirty);
+        assert_eq!(cartridge.read_ram(0xA000), 0xFF);
+
+        cartridge.write_rom(0x0000, 0x0A);
+        cartridge.write_ram(0xA000, 0x42);
+        assert!(cartridge.dirty);
+        assert_eq!(cartridge.read_ram(0xA000), 0x42);
+    }
+}
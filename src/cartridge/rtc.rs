@@ -0,0 +1,385 @@
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use crate::apu::CPU_FREQUENCY;
+
+pub const SECONDS_REGISTER: u8 = 0x08;
+pub const MINUTES_REGISTER: u8 = 0x09;
+pub const HOURS_REGISTER: u8 = 0x0A;
+pub const DAY_LOW_REGISTER: u8 = 0x0B;
+pub const DAY_HIGH_REGISTER: u8 = 0x0C;
+
+const DAY_HIGH_BIT_POSITION: u8 = 0;
+const HALT_BIT_POSITION: u8 = 6;
+const DAY_CARRY_BIT_POSITION: u8 = 7;
+
+const MAX_DAYS: u16 = 512;
+
+// Five u32 registers for the live clock, five for the latched copy and a u64
+// UNIX timestamp. This is the layout BGB and VBA-M append to .sav files.
+pub const SAVE_TRAILER_SIZE: usize = 48;
+// Older emulators store the timestamp as a u32
+pub const LEGACY_SAVE_TRAILER_SIZE: usize = 44;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum RtcMode {
+    // Advances with emulated CPU cycles, so fast-forwarding or pausing moves the clock with the game
+    EmulatedCycles,
+    // Follows the host's clock, just like the battery-powered crystal on a real cartridge
+    WallTime,
+}
+
+#[derive(Clone, Copy, Default)]
+struct ClockRegisters {
+    seconds: u8,
+    minutes: u8,
+    hours: u8,
+    day_low: u8,
+    day_high: u8,
+}
+
+impl ClockRegisters {
+    fn read(&self, register: u8) -> u8 {
+        match register {
+            SECONDS_REGISTER => self.seconds,
+            MINUTES_REGISTER => self.minutes,
+            HOURS_REGISTER => self.hours,
+            DAY_LOW_REGISTER => self.day_low,
+            DAY_HIGH_REGISTER => self.day_high,
+            _ => 0xFF,
+        }
+    }
+
+    fn to_save_bytes(self, bytes: &mut Vec<u8>) {
+        for register in [
+            self.seconds,
+            self.minutes,
+            self.hours,
+            self.day_low,
+            self.day_high,
+        ] {
+            bytes.extend_from_slice(&(register as u32).to_le_bytes());
+        }
+    }
+
+    fn from_save_bytes(bytes: &[u8]) -> ClockRegisters {
+        let register = |index: usize| bytes[index * 4];
+        ClockRegisters {
+            seconds: register(0),
+            minutes: register(1),
+            hours: register(2),
+            day_low: register(3),
+            day_high: register(4),
+        }
+    }
+}
+
+pub struct RealTimeClock {
+    mode: RtcMode,
+    live: ClockRegisters,
+    latched: ClockRegisters,
+    // Sub-second progress, in CPU cycles for emulated mode
+    cycles: u32,
+    // Last instant the live registers were brought up to date in wall time mode
+    last_update: SystemTime,
+    // The latch fires on a 0x00 write followed by a 0x01 write
+    latch_armed: bool,
+}
+
+impl RealTimeClock {
+    pub fn new(mode: RtcMode) -> RealTimeClock {
+        RealTimeClock {
+            mode,
+            live: ClockRegisters::default(),
+            latched: ClockRegisters::default(),
+            cycles: 0,
+            last_update: SystemTime::now(),
+            latch_armed: false,
+        }
+    }
+
+    pub fn set_mode(&mut self, mode: RtcMode) {
+        self.sync_wall_time();
+        self.mode = mode;
+        self.last_update = SystemTime::now();
+    }
+
+    // The RTC crystal runs at 32768 Hz, but counting in CPU clocks keeps the
+    // emulated clock in lockstep with everything else driven by the CPU
+    pub fn tick(&mut self, cycles: u32) {
+        if self.mode != RtcMode::EmulatedCycles || self.halted() {
+            return;
+        }
+        self.cycles += cycles;
+        while self.cycles >= CPU_FREQUENCY {
+            self.cycles -= CPU_FREQUENCY;
+            self.advance(1);
+        }
+    }
+
+    pub fn write_latch(&mut self, value: u8) {
+        if self.latch_armed && value == 0x01 {
+            self.sync_wall_time();
+            self.latched = self.live;
+        }
+        self.latch_armed = value == 0x00;
+    }
+
+    // Games only ever see the latched copy, so the value can't tick over halfway through a read
+    pub fn read(&self, register: u8) -> u8 {
+        self.latched.read(register)
+    }
+
+    pub fn write(&mut self, register: u8, value: u8) {
+        self.sync_wall_time();
+        match register {
+            SECONDS_REGISTER => {
+                self.live.seconds = value & 0x3F;
+                // Writing the seconds resets the divider feeding the seconds counter
+                self.cycles = 0;
+                self.last_update = SystemTime::now();
+            }
+            MINUTES_REGISTER => self.live.minutes = value & 0x3F,
+            HOURS_REGISTER => self.live.hours = value & 0x1F,
+            DAY_LOW_REGISTER => self.live.day_low = value,
+            DAY_HIGH_REGISTER => {
+                let was_halted = self.halted();
+                self.live.day_high = value & 0xC1;
+                if was_halted && !self.halted() {
+                    self.last_update = SystemTime::now();
+                }
+            }
+            _ => {}
+        }
+    }
+
+    pub fn save_trailer(&mut self) -> Vec<u8> {
+        self.sync_wall_time();
+        let mut bytes = Vec::with_capacity(SAVE_TRAILER_SIZE);
+        self.live.to_save_bytes(&mut bytes);
+        self.latched.to_save_bytes(&mut bytes);
+        let timestamp = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_secs();
+        bytes.extend_from_slice(&timestamp.to_le_bytes());
+        bytes
+    }
+
+    pub fn load_save_trailer(&mut self, bytes: &[u8]) {
+        let timestamp = match bytes.len() {
+            SAVE_TRAILER_SIZE => u64::from_le_bytes(bytes[40..48].try_into().unwrap()),
+            LEGACY_SAVE_TRAILER_SIZE => {
+                u32::from_le_bytes(bytes[40..44].try_into().unwrap()) as u64
+            }
+            _ => return,
+        };
+        self.live = ClockRegisters::from_save_bytes(&bytes[0..20]);
+        self.latched = ClockRegisters::from_save_bytes(&bytes[20..40]);
+        self.cycles = 0;
+        self.last_update = UNIX_EPOCH + Duration::from_secs(timestamp);
+
+        // Emulated time did not pass while the emulator was closed, only wall time did
+        if self.mode == RtcMode::WallTime {
+            self.sync_wall_time();
+        } else {
+            self.last_update = SystemTime::now();
+        }
+    }
+
+    fn halted(&self) -> bool {
+        (self.live.day_high >> HALT_BIT_POSITION) & 0b1 != 0
+    }
+
+    fn days(&self) -> u16 {
+        ((self.live.day_high as u16 >> DAY_HIGH_BIT_POSITION) & 0b1) << 8 | self.live.day_low as u16
+    }
+
+    fn set_days(&mut self, days: u16) {
+        self.live.day_low = (days & 0xFF) as u8;
+        self.live.day_high = (self.live.day_high & !(1 << DAY_HIGH_BIT_POSITION))
+            | (((days >> 8) & 0b1) as u8) << DAY_HIGH_BIT_POSITION;
+    }
+
+    fn sync_wall_time(&mut self) {
+        if self.mode != RtcMode::WallTime {
+            return;
+        }
+        let elapsed = SystemTime::now()
+            .duration_since(self.last_update)
+            .unwrap_or_default()
+            .as_secs();
+        if elapsed == 0 {
+            return;
+        }
+        // Keep the sub-second remainder so frequent syncs don't lose time
+        self.last_update += Duration::from_secs(elapsed);
+        if !self.halted() {
+            self.advance(elapsed);
+        }
+    }
+
+    fn advance(&mut self, mut seconds: u64) {
+        // Out of range values written by a game count up to the register's bit
+        // width and wrap without carrying, so step those one second at a time
+        while seconds > 0 && !self.in_range() {
+            self.tick_second();
+            seconds -= 1;
+        }
+        if seconds == 0 {
+            return;
+        }
+
+        let total = self.days() as u64 * 86_400
+            + self.live.hours as u64 * 3_600
+            + self.live.minutes as u64 * 60
+            + self.live.seconds as u64
+            + seconds;
+        self.live.seconds = (total % 60) as u8;
+        self.live.minutes = (total / 60 % 60) as u8;
+        self.live.hours = (total / 3_600 % 24) as u8;
+        let days = total / 86_400;
+        if days >= MAX_DAYS as u64 {
+            self.live.day_high |= 1 << DAY_CARRY_BIT_POSITION;
+        }
+        self.set_days((days % MAX_DAYS as u64) as u16);
+    }
+
+    fn in_range(&self) -> bool {
+        self.live.seconds < 60 && self.live.minutes < 60 && self.live.hours < 24
+    }
+
+    fn tick_second(&mut self) {
+        self.live.seconds = (self.live.seconds + 1) & 0x3F;
+        if self.live.seconds != 60 {
+            return;
+        }
+        self.live.seconds = 0;
+
+        self.live.minutes = (self.live.minutes + 1) & 0x3F;
+        if self.live.minutes != 60 {
+            return;
+        }
+        self.live.minutes = 0;
+
+        self.live.hours = (self.live.hours + 1) & 0x1F;
+        if self.live.hours != 24 {
+            return;
+        }
+        self.live.hours = 0;
+
+        let days = self.days() + 1;
+        if days >= MAX_DAYS {
+            self.live.day_high |= 1 << DAY_CARRY_BIT_POSITION;
+        }
+        self.set_days(days % MAX_DAYS);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn latch(rtc: &mut RealTimeClock) {
+        rtc.write_latch(0x00);
+        rtc.write_latch(0x01);
+    }
+
+    // 1 day, 5:15:30 with the day high bit and the carry set
+    fn set_clock(rtc: &mut RealTimeClock) {
+        rtc.write(SECONDS_REGISTER, 30);
+        rtc.write(MINUTES_REGISTER, 15);
+        rtc.write(HOURS_REGISTER, 5);
+        rtc.write(DAY_LOW_REGISTER, 0x23);
+        rtc.write(DAY_HIGH_REGISTER, 0x81);
+    }
+
+    fn registers(rtc: &RealTimeClock) -> [u8; 5] {
+        [
+            rtc.read(SECONDS_REGISTER),
+            rtc.read(MINUTES_REGISTER),
+            rtc.read(HOURS_REGISTER),
+            rtc.read(DAY_LOW_REGISTER),
+            rtc.read(DAY_HIGH_REGISTER),
+        ]
+    }
+
+    #[test]
+    fn save_trailer_uses_the_48_byte_layout() {
+        let mut rtc = RealTimeClock::new(RtcMode::EmulatedCycles);
+        set_clock(&mut rtc);
+        latch(&mut rtc);
+
+        let trailer = rtc.save_trailer();
+        assert_eq!(trailer.len(), SAVE_TRAILER_SIZE);
+        // Live registers, then the latched copy, each as little-endian u32s
+        for base in [0, 20] {
+            assert_eq!(trailer[base..base + 4], 30u32.to_le_bytes());
+            assert_eq!(trailer[base + 4..base + 8], 15u32.to_le_bytes());
+            assert_eq!(trailer[base + 8..base + 12], 5u32.to_le_bytes());
+            assert_eq!(trailer[base + 12..base + 16], 0x23u32.to_le_bytes());
+            assert_eq!(trailer[base + 16..base + 20], 0x81u32.to_le_bytes());
+        }
+        let timestamp = u64::from_le_bytes(trailer[40..48].try_into().unwrap());
+        let now = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap()
+            .as_secs();
+        assert!(now - timestamp <= 1);
+    }
+
+    #[test]
+    fn save_trailer_round_trips() {
+        let mut rtc = RealTimeClock::new(RtcMode::EmulatedCycles);
+        set_clock(&mut rtc);
+        latch(&mut rtc);
+        let trailer = rtc.save_trailer();
+
+        let mut restored = RealTimeClock::new(RtcMode::EmulatedCycles);
+        restored.load_save_trailer(&trailer);
+        assert_eq!(registers(&restored), [30, 15, 5, 0x23, 0x81]);
+        latch(&mut restored);
+        assert_eq!(registers(&restored), [30, 15, 5, 0x23, 0x81]);
+        assert_eq!(restored.save_trailer()[..40], trailer[..40]);
+    }
+
+    #[test]
+    fn loads_legacy_44_byte_trailer() {
+        let mut rtc = RealTimeClock::new(RtcMode::EmulatedCycles);
+        set_clock(&mut rtc);
+        latch(&mut rtc);
+        let mut trailer = rtc.save_trailer();
+        trailer.truncate(LEGACY_SAVE_TRAILER_SIZE);
+
+        let mut restored = RealTimeClock::new(RtcMode::EmulatedCycles);
+        restored.load_save_trailer(&trailer);
+        assert_eq!(registers(&restored), [30, 15, 5, 0x23, 0x81]);
+    }
+
+    #[test]
+    fn wall_time_catches_up_with_time_spent_closed() {
+        let mut rtc = RealTimeClock::new(RtcMode::EmulatedCycles);
+        rtc.write(MINUTES_REGISTER, 10);
+        let mut trailer = rtc.save_trailer();
+        let hour_ago = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap()
+            .as_secs()
+            - 3_600;
+        trailer[40..48].copy_from_slice(&hour_ago.to_le_bytes());
+
+        let mut restored = RealTimeClock::new(RtcMode::WallTime);
+        restored.load_save_trailer(&trailer);
+        latch(&mut restored);
+        assert_eq!(restored.read(HOURS_REGISTER), 1);
+        assert_eq!(restored.read(MINUTES_REGISTER), 10);
+    }
+
+    #[test]
+    fn ignores_trailer_of_unknown_size() {
+        let mut rtc = RealTimeClock::new(RtcMode::EmulatedCycles);
+        set_clock(&mut rtc);
+        latch(&mut rtc);
+        rtc.load_save_trailer(&[0xFF; 12]);
+        assert_eq!(registers(&rtc), [30, 15, 5, 0x23, 0x81]);
+    }
+}
@@ -0,0 +1,109 @@
+use std::fs;
+use std::io::{self, Write};
+use std::path::{Path, PathBuf};
+use std::time::{Duration, Instant};
+
+pub const DEFAULT_SAVE_INTERVAL: Duration = Duration::from_secs(5);
+
+// Battery-backed RAM persisted next to the ROM
+pub struct SaveFile {
+    path: PathBuf,
+    interval: Duration,
+    // Last write attempt, successful or not
+    last_write: Instant,
+}
+
+impl SaveFile {
+    pub fn new<P: AsRef<Path>>(path: P) -> SaveFile {
+        SaveFile {
+            path: path.as_ref().to_path_buf(),
+            interval: DEFAULT_SAVE_INTERVAL,
+            last_write: Instant::now(),
+        }
+    }
+
+    // `game.gb` saves to `game.sav`, which is what every other emulator looks for
+    pub fn for_rom<P: AsRef<Path>>(rom_path: P) -> SaveFile {
+        SaveFile::new(rom_path.as_ref().with_extension("sav"))
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    pub fn set_interval(&mut self, interval: Duration) {
+        self.interval = interval;
+    }
+
+    pub fn is_due(&self) -> bool {
+        self.last_write.elapsed() >= self.interval
+    }
+
+    // A missing file just means the game has never been saved
+    pub fn read(&self) -> io::Result<Option<Vec<u8>>> {
+        match fs::read(&self.path) {
+            Ok(data) => Ok(Some(data)),
+            Err(error) if error.kind() == io::ErrorKind::NotFound => Ok(None),
+            Err(error) => Err(error),
+        }
+    }
+
+    // Writes to a temporary file and renames it over the old save, so a crash
+    // halfway through leaves either the old or the new save but never a torn one.
+    // A failed write waits for the next interval like a successful one, so a full
+    // disk isn't hammered with retries, and doesn't leave the temporary file behind.
+    pub fn write(&mut self, data: &[u8]) -> io::Result<()> {
+        self.last_write = Instant::now();
+        let temporary_path = self.temporary_path();
+        let result = write_synced(&temporary_path, data)
+            .and_then(|()| fs::rename(&temporary_path, &self.path));
+        if result.is_err() {
+            let _ = fs::remove_file(&temporary_path);
+        }
+        result
+    }
+
+    fn temporary_path(&self) -> PathBuf {
+        let mut temporary_path = self.path.clone().into_os_string();
+        temporary_path.push(".tmp");
+        PathBuf::from(temporary_path)
+    }
+}
+
+fn write_synced(path: &Path, data: &[u8]) -> io::Result<()> {
+    let mut file = fs::File::create(path)?;
+    file.write_all(data)?;
+    file.sync_all()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn failed_write_waits_for_the_next_interval() {
+        let mut save_file = SaveFile::new("/nonexistent/directory/game.sav");
+        save_file.set_interval(Duration::from_secs(60));
+        save_file.last_write -= Duration::from_secs(120);
+        assert!(save_file.is_due());
+
+        assert!(save_file.write(&[0x12]).is_err());
+        assert!(!save_file.is_due());
+    }
+
+    #[test]
+    fn failed_rename_removes_the_temporary_file() {
+        // A directory where the save should go makes the rename fail after the write
+        let directory = std::env::temp_dir().join(format!("gb-em-save-{}", std::process::id()));
+        let save_path = directory.join("game.sav");
+        fs::create_dir_all(save_path.join("blocker")).unwrap();
+
+        let mut save_file = SaveFile::new(&save_path);
+        let result = save_file.write(&[0x12]);
+        let temporary_exists = save_file.temporary_path().exists();
+        fs::remove_dir_all(&directory).unwrap();
+
+        assert!(result.is_err());
+        assert!(!temporary_exists);
+    }
+}
@@ -1,52 +1,218 @@
 use crate::instructions::Instruction;
+use crate::interrupts::{Interrupt, INTERRUPT_DISPATCH_CYCLES};
+use crate::joypad::Button;
 use crate::memory::MemoryBus;
+use crate::memory::T_CYCLES_PER_M_CYCLE;
 use crate::registers::Registers;
 
 use crate::instructions_execution::{
     arithmetic, bit, conditional, load, logical, misc, rotate, shift, stack,
 };
 
-struct CPU {
+pub struct CPU {
     registers: Registers,
     pc: u16,
     sp: u16,
     bus: MemoryBus,
+    // Interrupt master enable
+    ime: bool,
+    // EI was executed and IME turns on once the following instruction finishes
+    ime_scheduled: bool,
+    // Sleeping in HALT until IE & IF != 0
+    halted: bool,
+    // The next opcode fetch won't increment PC
+    halt_bug: bool,
+    // Sleeping in STOP until a joypad line goes low
+    stopped: bool,
+    // Invalid opcode that hung the CPU, which then never runs another instruction
+    locked_up: Option<u8>,
+    // T-cycles executed since power on
+    cycles: u64,
+    rumble_handler: Option<Box<dyn FnMut(bool)>>,
 }
 
 impl CPU {
-    fn step(&mut self) {
-        let mut instruction_byte = self.bus.read_byte(self.pc);
+    pub fn new(bus: MemoryBus) -> CPU {
+        CPU {
+            registers: Registers::new(),
+            pc: 0x0100,
+            sp: 0xFFFE,
+            bus,
+            ime: false,
+            ime_scheduled: false,
+            halted: false,
+            halt_bug: false,
+            stopped: false,
+            locked_up: None,
+            cycles: 0,
+            rumble_handler: None,
+        }
+    }
+
+    // Registers a callback that receives the new motor state whenever a rumble cartridge switches it
+    pub fn on_rumble<F: FnMut(bool) + 'static>(&mut self, handler: F) {
+        self.rumble_handler = Some(Box::new(handler));
+    }
+
+    pub fn cycles(&self) -> u64 {
+        self.cycles
+    }
+
+    pub fn pc(&self) -> u16 {
+        self.pc
+    }
+
+    // The opcode the CPU hung on, with PC still pointing at it
+    pub fn locked_up(&self) -> Option<u8> {
+        self.locked_up
+    }
+
+    pub fn sp(&self) -> u16 {
+        self.sp
+    }
+
+    pub fn registers(&self) -> &Registers {
+        &self.registers
+    }
+
+    pub fn registers_mut(&mut self) -> &mut Registers {
+        &mut self.registers
+    }
+
+    pub fn bus(&self) -> &MemoryBus {
+        &self.bus
+    }
+
+    pub fn bus_mut(&mut self) -> &mut MemoryBus {
+        &mut self.bus
+    }
+
+    // Enters a subroutine the way CALL does, with the stack pointer reset first.
+    // Lets the host run code in the ROM and notice when it returns to `return_address`.
+    pub fn call(&mut self, address: u16, stack_pointer: u16, return_address: u16) {
+        self.sp = stack_pointer;
+        stack::push_word(&mut self.bus, &mut self.sp, return_address);
+        self.pc = address;
+        self.halted = false;
+    }
+
+    pub fn press(&mut self, button: Button) {
+        self.bus.press_button(button);
+    }
+
+    pub fn release(&mut self, button: Button) {
+        self.bus.release_button(button);
+    }
+
+    // Runs one instruction, or dispatches one interrupt, and returns the T-cycles it took
+    pub fn step(&mut self) -> u32 {
+        // Like on hardware the rest of the console keeps running around a hung CPU
+        if self.locked_up.is_some() {
+            return self.advance(1);
+        }
+
+        // Everything is frozen in STOP, including the timer and the LCD. It ends when a
+        // selected joypad line goes low, whatever IF held when STOP was entered.
+        if self.stopped {
+            if !self.bus.joypad_line_low() {
+                self.cycles += T_CYCLES_PER_M_CYCLE as u64;
+                return T_CYCLES_PER_M_CYCLE;
+            }
+            self.stopped = false;
+        }
+
+        // HALT wakes up on a pending interrupt even with IME off; it just isn't serviced then
+        if self.halted {
+            if self.bus.pending_interrupts() == 0 {
+                return self.advance(1);
+            }
+            self.halted = false;
+        }
+
+        let interrupt_cycles = self.service_interrupt();
+        if interrupt_cycles > 0 {
+            return self.advance(interrupt_cycles);
+        }
+
+        let enable_ime = self.ime_scheduled;
+
+        let opcode_address = self.pc;
+        if self.halt_bug {
+            // Operands are fetched starting from the opcode's own address
+            self.halt_bug = false;
+            self.pc = self.pc.wrapping_sub(1);
+        }
+
+        let mut instruction_byte = self.bus.read_byte(opcode_address);
         let prefixed = instruction_byte == 0xCB;
         if prefixed {
             instruction_byte = self.bus.read_byte(self.pc + 1);
         }
 
-        let next_pc = if let Some(instruction) = Instruction::from_byte(instruction_byte, prefixed)
-        {
-            self.execute(instruction)
-        } else {
-            let description = format!(
-                "0x{}:{:x}",
-                if prefixed { "cb" } else { "" },
-                instruction_byte
-            );
-            panic!("Unknown instruction found for: {}", description);
+        // Every CB-prefixed opcode exists, so only the eleven holes in the main table get here
+        let Some(instruction) = Instruction::from_byte(instruction_byte, prefixed) else {
+            self.locked_up = Some(instruction_byte);
+            self.pc = opcode_address;
+            return self.advance(1);
         };
+        let (next_pc, instruction_cycles) = self.execute(instruction);
 
         self.pc = next_pc;
+        let t_cycles = self.advance(instruction_cycles);
+
+        // A DI in between cancels the pending EI
+        if enable_ime && self.ime_scheduled {
+            self.ime = true;
+            self.ime_scheduled = false;
+        }
+
+        if let Some(motor_on) = self.bus.cartridge.take_rumble_change() {
+            if let Some(handler) = &mut self.rumble_handler {
+                handler(motor_on);
+            }
+        }
+
+        t_cycles
+    }
+
+    // Lets the rest of the hardware catch up with the time the CPU just spent
+    fn advance(&mut self, m_cycles: u8) -> u32 {
+        let t_cycles = m_cycles as u32 * T_CYCLES_PER_M_CYCLE;
+        self.cycles += t_cycles as u64;
+        self.bus.tick(t_cycles);
+        t_cycles
     }
 
-    fn execute(&mut self, instruction: Instruction) -> u16 {
-        match instruction {
+    // Jumps to the highest priority pending interrupt, returning the M-cycles spent doing so
+    fn service_interrupt(&mut self) -> u8 {
+        if !self.ime {
+            return 0;
+        }
+        let interrupt = match Interrupt::highest_priority(self.bus.pending_interrupts()) {
+            Some(interrupt) => interrupt,
+            None => return 0,
+        };
+
+        self.ime = false;
+        self.bus.clear_interrupt(interrupt);
+        stack::push_word(&mut self.bus, &mut self.sp, self.pc);
+        self.pc = interrupt.vector();
+        INTERRUPT_DISPATCH_CYCLES
+    }
+
+    // Returns the address of the next instruction and the M-cycles this one took
+    fn execute(&mut self, instruction: Instruction) -> (u16, u8) {
+        let cycles = instruction.cycles(&self.registers.f);
+        let next_pc = match instruction {
             // JUMP Instructions
             Instruction::JP(test) => {
-                conditional::jump(&mut self.registers, self.pc, &mut self.bus, test)
+                conditional::jump(&mut self.registers, self.pc, &self.bus, test)
             }
 
             Instruction::JPL => conditional::jpl(self.registers),
 
             Instruction::JR(test) => {
-                conditional::jump_relative(&mut self.registers, self.pc, &mut self.bus, test)
+                conditional::jump_relative(&mut self.registers, self.pc, &self.bus, test)
             }
 
             // addition instructions
@@ -193,13 +359,21 @@ impl CPU {
             }
 
             /* Stack instructions */
-            Instruction::PUSH(target) => {
-                stack::push(&mut self.registers, target, &mut self.bus, self.pc)
-            } // TODO: Implement Push
+            Instruction::PUSH(target) => stack::push(
+                &mut self.registers,
+                target,
+                &mut self.bus,
+                self.pc,
+                &mut self.sp,
+            ),
 
-            Instruction::POP(target) => {
-                stack::pop(&mut self.registers, target, &mut self.bus, self.pc)
-            } // TODO: Implement Pop
+            Instruction::POP(target) => stack::pop(
+                &mut self.registers,
+                target,
+                &mut self.bus,
+                self.pc,
+                &mut self.sp,
+            ),
 
             Instruction::CALL(test) => conditional::call(
                 &mut self.registers,
@@ -226,19 +400,193 @@ impl CPU {
 
             Instruction::CPL => misc::cpl(&mut self.registers, self.pc),
 
-            Instruction::RETI => misc::reti(&mut self.registers, self.pc),
+            Instruction::RETI => misc::reti(&mut self.bus, &mut self.sp, &mut self.ime),
 
-            Instruction::RST(value) => misc::rst(&mut self.registers, self.pc, value),
+            Instruction::RST(vector) => misc::rst(&mut self.bus, &mut self.sp, self.pc, vector),
 
             Instruction::NOP => misc::nop(self.pc),
 
-            Instruction::STOP => misc::stop(&mut self.registers, self.pc),
+            Instruction::STOP => misc::stop(&mut self.bus, &mut self.stopped, self.pc),
+
+            Instruction::HALT => misc::halt(
+                &self.bus,
+                self.ime,
+                &mut self.halted,
+                &mut self.halt_bug,
+                self.pc,
+            ),
+
+            Instruction::DI => misc::di(&mut self.ime, &mut self.ime_scheduled, self.pc),
+
+            Instruction::EI => misc::ei(&mut self.ime_scheduled, self.pc),
+        };
+        (next_pc, cycles)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::cartridge::header::test_rom::{self, PROGRAM_START};
+    use crate::cartridge::Cartridge;
+    use crate::interrupts::{INTERRUPT_ENABLE_REGISTER, INTERRUPT_FLAG_REGISTER, INTERRUPT_MASK};
+
+    // CPU about to run `program`, with the stack in HRAM
+    fn cpu_with_program(program: &[u8]) -> CPU {
+        let cartridge = Cartridge::from_bytes(test_rom::with_program(program)).unwrap();
+        let mut cpu = CPU::new(MemoryBus::new(cartridge));
+        cpu.pc = PROGRAM_START;
+        cpu
+    }
+
+    #[test]
+    fn jr_jumps_forward_from_the_end_of_the_instruction() {
+        // JR +5
+        let mut cpu = cpu_with_program(&[0x18, 0x05]);
+        assert_eq!(cpu.step(), 12);
+        assert_eq!(cpu.pc, PROGRAM_START + 7);
+    }
+
+    #[test]
+    fn jr_jumps_backward_with_a_negative_offset() {
+        // NOP; NOP; JR NZ,-4 back to the first NOP
+        let mut cpu = cpu_with_program(&[0x00, 0x00, 0x20, 0xFC]);
+        cpu.pc = PROGRAM_START + 2;
+        cpu.registers.f.zero = false;
+        assert_eq!(cpu.step(), 12);
+        assert_eq!(cpu.pc, PROGRAM_START);
+    }
+
+    #[test]
+    fn jr_not_taken_skips_the_operand() {
+        // JR NZ,+5 with Z set
+        let mut cpu = cpu_with_program(&[0x20, 0x05]);
+        cpu.registers.f.zero = true;
+        assert_eq!(cpu.step(), 8);
+        assert_eq!(cpu.pc, PROGRAM_START + 2);
+    }
+
+    #[test]
+    fn ld_hl_sp_offset_takes_flags_from_the_low_byte() {
+        // LD HL,SP-8
+        let mut cpu = cpu_with_program(&[0xF8, 0xF8]);
+        cpu.sp = 0xFFF8;
+        assert_eq!(cpu.step(), 12);
+        assert_eq!(cpu.registers.get_hl(), 0xFFF0);
+        assert_eq!(cpu.pc, PROGRAM_START + 2);
+        assert!(!cpu.registers.f.zero && !cpu.registers.f.subtract);
+        assert!(cpu.registers.f.half_carry && cpu.registers.f.carry);
+
+        // LD HL,SP+1
+        let mut cpu = cpu_with_program(&[0xF8, 0x01]);
+        cpu.sp = 0xD000;
+        cpu.step();
+        assert_eq!(cpu.registers.get_hl(), 0xD001);
+        assert!(!cpu.registers.f.half_carry && !cpu.registers.f.carry);
+    }
+
+    #[test]
+    fn invalid_opcodes_lock_the_cpu_up() {
+        for opcode in [
+            0xD3, 0xDB, 0xDD, 0xE3, 0xE4, 0xEB, 0xEC, 0xED, 0xF4, 0xFC, 0xFD,
+        ] {
+            let mut cpu = cpu_with_program(&[opcode]);
+            cpu.step();
+            assert_eq!(cpu.locked_up(), Some(opcode));
+            assert_eq!(cpu.pc, PROGRAM_START);
+            // Time still passes, but PC never moves again
+            assert_eq!(cpu.step(), 4);
+            assert_eq!(cpu.pc, PROGRAM_START);
+        }
+    }
+
+    fn request_interrupts(cpu: &mut CPU, enabled: u8, requested: u8) {
+        cpu.bus.set_byte(INTERRUPT_ENABLE_REGISTER, enabled);
+        cpu.bus.set_byte(INTERRUPT_FLAG_REGISTER, requested);
+    }
+
+    fn requested_interrupts(cpu: &CPU) -> u8 {
+        cpu.bus.read_byte(INTERRUPT_FLAG_REGISTER) & INTERRUPT_MASK
+    }
 
-            Instruction::HALT => misc::halt(&mut self.registers, self.pc),
+    #[test]
+    fn highest_priority_interrupt_is_dispatched_in_5_m_cycles() {
+        let mut cpu = cpu_with_program(&[0x00]);
+        cpu.ime = true;
+        let requested = Interrupt::LcdStat.bit() | Interrupt::Timer.bit() | Interrupt::Serial.bit();
+        request_interrupts(&mut cpu, INTERRUPT_MASK, requested);
+
+        assert_eq!(cpu.step(), 5 * T_CYCLES_PER_M_CYCLE);
+        assert_eq!(cpu.pc, Interrupt::LcdStat.vector());
+        assert!(!cpu.ime);
+        assert_eq!(
+            requested_interrupts(&cpu),
+            requested & !Interrupt::LcdStat.bit()
+        );
+        // The interrupted PC is on the stack
+        assert_eq!(cpu.sp, 0xFFFC);
+        assert_eq!(stack::pop_word(&cpu.bus, &mut cpu.sp), PROGRAM_START);
+    }
 
-            Instruction::DI => misc::di(&mut self.registers, self.pc),
+    #[test]
+    fn ei_takes_effect_after_the_next_instruction() {
+        // EI; NOP; NOP
+        let mut cpu = cpu_with_program(&[0xFB, 0x00, 0x00]);
+        request_interrupts(&mut cpu, Interrupt::VBlank.bit(), Interrupt::VBlank.bit());
+
+        cpu.step();
+        assert_eq!(cpu.pc, PROGRAM_START + 1);
+        cpu.step();
+        assert_eq!(cpu.pc, PROGRAM_START + 2);
+        cpu.step();
+        assert_eq!(cpu.pc, Interrupt::VBlank.vector());
+    }
 
-            Instruction::EI => misc::ei(&mut self.registers, self.pc),
+    #[test]
+    fn di_right_after_ei_never_services() {
+        // EI; DI; NOP; NOP
+        let mut cpu = cpu_with_program(&[0xFB, 0xF3, 0x00, 0x00]);
+        request_interrupts(&mut cpu, Interrupt::VBlank.bit(), Interrupt::VBlank.bit());
+        for _ in 0..4 {
+            cpu.step();
         }
+        assert_eq!(cpu.pc, PROGRAM_START + 4);
+        assert!(!cpu.ime);
+        assert_eq!(requested_interrupts(&cpu), Interrupt::VBlank.bit());
+    }
+
+    #[test]
+    fn halt_wakes_without_dispatching_when_ime_is_off() {
+        // HALT; NOP
+        let mut cpu = cpu_with_program(&[0x76, 0x00]);
+        request_interrupts(&mut cpu, Interrupt::Serial.bit(), 0);
+        cpu.step();
+        assert!(cpu.halted);
+        assert_eq!(cpu.step(), 4);
+        assert_eq!(cpu.pc, PROGRAM_START + 1);
+
+        cpu.bus
+            .set_byte(INTERRUPT_FLAG_REGISTER, Interrupt::Serial.bit());
+        cpu.step();
+        assert!(!cpu.halted);
+        // The NOP after HALT ran instead of the handler, and the request is still there
+        assert_eq!(cpu.pc, PROGRAM_START + 2);
+        assert_eq!(requested_interrupts(&cpu), Interrupt::Serial.bit());
+    }
+
+    #[test]
+    fn halt_bug_runs_the_next_byte_twice() {
+        // HALT; INC A; NOP, with an interrupt already pending and IME off
+        let mut cpu = cpu_with_program(&[0x76, 0x3C, 0x00]);
+        cpu.registers.a = 0;
+        request_interrupts(&mut cpu, Interrupt::Timer.bit(), Interrupt::Timer.bit());
+
+        cpu.step();
+        assert!(!cpu.halted);
+        cpu.step();
+        assert_eq!(cpu.pc, PROGRAM_START + 1);
+        cpu.step();
+        assert_eq!(cpu.pc, PROGRAM_START + 2);
+        assert_eq!(cpu.registers.a, 2);
     }
 }
@@ -0,0 +1,167 @@
+use std::io;
+use std::path::Path;
+
+use crate::cartridge::rtc::RtcMode;
+use crate::cartridge::{Cartridge, CartridgeError};
+use crate::cpu::CPU;
+use crate::joypad::Button;
+use crate::memory::MemoryBus;
+use crate::ppu::{PpuBackend, DOTS_PER_FRAME};
+use crate::serial::SerialDevice;
+
+// The whole console behind one type, for frontends and tools that embed the emulator.
+// Anything not covered here is still reachable through `cpu` and `cpu_mut`.
+pub struct GameBoy {
+    cpu: CPU,
+    // First periodic save that failed, kept for `flush` so emulation carries on meanwhile
+    save_error: Option<io::Error>,
+}
+
+impl GameBoy {
+    pub fn new(cartridge: Cartridge) -> GameBoy {
+        GameBoy::with_ppu_backend(cartridge, PpuBackend::Scanline)
+    }
+
+    pub fn with_ppu_backend(cartridge: Cartridge, ppu_backend: PpuBackend) -> GameBoy {
+        GameBoy {
+            cpu: CPU::new(MemoryBus::with_ppu_backend(cartridge, ppu_backend)),
+            save_error: None,
+        }
+    }
+
+    // Picks up the `.sav` file next to the ROM for battery-backed cartridges
+    pub fn load_rom<P: AsRef<Path>>(path: P) -> Result<GameBoy, CartridgeError> {
+        Ok(GameBoy::new(Cartridge::from_file(path)?))
+    }
+
+    pub fn from_rom_bytes(rom: Vec<u8>) -> Result<GameBoy, CartridgeError> {
+        Ok(GameBoy::new(Cartridge::from_bytes(rom)?))
+    }
+
+    // Runs until the PPU finishes a frame and returns the T-cycles that took. With the
+    // LCD off no frame ever comes, so it stops after a frame's worth of time instead.
+    // Battery saves are written out here once their interval has passed. A failed write
+    // doesn't stop emulation, it's reported by the next flush.
+    pub fn run_frame(&mut self) -> u64 {
+        let start = self.cpu.cycles();
+        self.run_frame_until(|_| false);
+        self.cpu.cycles() - start
+    }
+
+    // Like run_frame, but checks `stop` after every instruction and returns true
+    // if it ended the frame early
+    pub fn run_frame_until<F: FnMut(&CPU) -> bool>(&mut self, mut stop: F) -> bool {
+        let start = self.cpu.cycles();
+        let stopped = loop {
+            self.cpu.step();
+            if stop(&self.cpu) {
+                break true;
+            }
+            let speed = if self.cpu.bus().double_speed() { 2 } else { 1 };
+            if self.cpu.bus_mut().ppu.take_frame_ready()
+                || self.cpu.cycles() - start >= (DOTS_PER_FRAME * speed) as u64
+            {
+                break false;
+            }
+        };
+        if let Err(error) = self.cpu.bus_mut().cartridge.flush_save_if_due() {
+            self.save_error.get_or_insert(error);
+        }
+        stopped
+    }
+
+    // Runs one instruction, or dispatches one interrupt, and returns the T-cycles it took
+    pub fn step_instruction(&mut self) -> u32 {
+        self.cpu.step()
+    }
+
+    // One shade from 0 (white) to 3 (black) per pixel, SCREEN_WIDTH x SCREEN_HEIGHT
+    pub fn framebuffer(&self) -> &[u8] {
+        self.cpu.bus().ppu.framebuffer()
+    }
+
+    pub fn framebuffer_rgba(&self) -> Vec<u8> {
+        self.cpu.bus().ppu.framebuffer_rgba()
+    }
+
+    // Interleaved stereo samples produced since the last call. Only the latest few
+    // seconds are kept, so hosts without audio can leave them alone.
+    pub fn audio_samples(&mut self) -> Vec<f32> {
+        self.cpu.bus_mut().apu.take_samples()
+    }
+
+    pub fn set_sample_rate(&mut self, sample_rate: u32) {
+        self.cpu.bus_mut().apu.set_sample_rate(sample_rate);
+    }
+
+    pub fn press(&mut self, button: Button) {
+        self.cpu.press(button);
+    }
+
+    pub fn release(&mut self, button: Button) {
+        self.cpu.release(button);
+    }
+
+    pub fn on_rumble<F: FnMut(bool) + 'static>(&mut self, handler: F) {
+        self.cpu.on_rumble(handler);
+    }
+
+    // Only matters for MBC3 cartridges with a clock. Emulated cycles make runs
+    // deterministic, wall time keeps the clock right across sessions.
+    pub fn set_rtc_mode(&mut self, mode: RtcMode) {
+        self.cpu.bus_mut().cartridge.set_rtc_mode(mode);
+    }
+
+    pub fn connect_serial(&mut self, device: Box<dyn SerialDevice>) {
+        self.cpu.bus_mut().serial.connect(device);
+    }
+
+    // Writes out the battery save and whatever the serial device still buffers, and
+    // reports a periodic save that failed since the last call. Everything is flushed even
+    // when one part fails. The cartridge, printer and WAV writer also write out on drop,
+    // but can't report errors from there, so hosts call this before exiting.
+    pub fn flush(&mut self) -> io::Result<()> {
+        let earlier = self.save_error.take().map_or(Ok(()), Err);
+        let bus = self.cpu.bus_mut();
+        let save = bus.cartridge.flush();
+        earlier.and(save).and(bus.serial.flush())
+    }
+
+    pub fn cpu(&self) -> &CPU {
+        &self.cpu
+    }
+
+    pub fn cpu_mut(&mut self) -> &mut CPU {
+        &mut self.cpu
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::cartridge::header::test_rom;
+    use crate::cartridge::save::SaveFile;
+    use std::time::Duration;
+
+    const MBC1_RAM_BATTERY: u8 = 0x03;
+    const RAM_SIZE_8_KIB: u8 = 0x02;
+
+    #[test]
+    fn failed_periodic_save_is_reported_by_flush() {
+        let rom = test_rom::build(MBC1_RAM_BATTERY, 32 * 1024, RAM_SIZE_8_KIB);
+        let mut cartridge = Cartridge::from_bytes(rom).unwrap();
+        cartridge
+            .attach_save_file(SaveFile::new("/nonexistent/directory/game.sav"))
+            .unwrap();
+        cartridge.set_save_interval(Duration::ZERO);
+        cartridge.write_rom(0x0000, 0x0A);
+        cartridge.write_ram(0xA000, 0x42);
+
+        // The ROM is all NOPs, so PC just counts up
+        let mut gameboy = GameBoy::new(cartridge);
+        assert!(gameboy.run_frame_until(|cpu| cpu.pc() == 0x0110));
+        assert!(gameboy.save_error.is_some());
+        assert!(gameboy.flush().is_err());
+        assert!(gameboy.save_error.is_none());
+    }
+}
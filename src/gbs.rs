@@ -0,0 +1,406 @@
+use std::fmt;
+use std::fs;
+use std::io;
+use std::path::Path;
+
+use crate::apu::{Apu, CPU_FREQUENCY, NR50_REGISTER, NR51_REGISTER, NR52_REGISTER};
+use crate::cartridge::header::{header_checksum, NINTENDO_LOGO};
+use crate::cartridge::{Cartridge, CartridgeError, ROM_BANK_SIZE};
+use crate::cpu::CPU;
+use crate::memory::{MemoryBus, T_CYCLES_PER_M_CYCLE};
+use crate::ppu::DOTS_PER_FRAME;
+use crate::timer::{TAC_REGISTER, TMA_REGISTER};
+
+const SIGNATURE: &[u8; 3] = b"GBS";
+const SUPPORTED_VERSION: u8 = 1;
+const HEADER_SIZE: usize = 0x70;
+
+const VERSION_ADDRESS: usize = 0x03;
+const SONG_COUNT_ADDRESS: usize = 0x04;
+const FIRST_SONG_ADDRESS: usize = 0x05;
+const LOAD_ADDRESS: usize = 0x06;
+const INIT_ADDRESS: usize = 0x08;
+const PLAY_ADDRESS: usize = 0x0A;
+const STACK_POINTER_ADDRESS: usize = 0x0C;
+const TIMER_MODULO_ADDRESS: usize = 0x0E;
+const TIMER_CONTROL_ADDRESS: usize = 0x0F;
+const TITLE_START: usize = 0x10;
+const AUTHOR_START: usize = 0x30;
+const COPYRIGHT_START: usize = 0x50;
+const TEXT_FIELD_LENGTH: usize = 32;
+
+// The payload can't overlap the RST vectors or the cartridge header and has to start in ROM
+const MIN_LOAD_ADDRESS: u16 = 0x0400;
+const MAX_LOAD_ADDRESS: u16 = 0x7FFF;
+
+// Timer-driven rips set bit 2 of TAC; bit 7 asks for CGB double speed
+const TAC_TIMER_ENABLE: u8 = 0x04;
+const TAC_DOUBLE_SPEED: u8 = 0x80;
+const TAC_CLOCK_SELECT_MASK: u8 = 0x03;
+
+// The synthetic cartridge is an MBC5 with 8 KiB of RAM, which rips switch banks on
+// by writing to 0x2000 just like on MBC1
+const CARTRIDGE_TYPE_MBC5_RAM: u8 = 0x1A;
+const RAM_SIZE_8_KIB: u8 = 0x02;
+// Title through mask ROM version, which the header checksum covers
+const CARTRIDGE_INFO_START: usize = 0x0134;
+const CARTRIDGE_INFO_END: usize = 0x014C;
+const CARTRIDGE_TYPE_ADDRESS: usize = 0x0147;
+const ROM_SIZE_ADDRESS: usize = 0x0148;
+const RAM_SIZE_ADDRESS: usize = 0x0149;
+const HEADER_CHECKSUM_ADDRESS: usize = 0x014D;
+const LOGO_START: usize = 0x0104;
+const MIN_ROM_SIZE: usize = 2 * ROM_BANK_SIZE;
+const RAM_ENABLE_REGISTER: u16 = 0x0000;
+const RAM_ENABLE_VALUE: u8 = 0x0A;
+const ROM_BANK_REGISTER: u16 = 0x2000;
+
+// RST n lands at load address + n in a GBS rip
+const RST_VECTORS: [u16; 8] = [0x00, 0x08, 0x10, 0x18, 0x20, 0x28, 0x30, 0x38];
+const JP_OPCODE: u8 = 0xC3;
+
+// Routines return here, somewhere no code can live, which is how the player
+// notices that init or play is done
+const RETURN_ADDRESS: u16 = 0xFEA0;
+// A routine still running after a second of emulated time is stuck, most likely
+// waiting in HALT for an interrupt that never comes
+const MAX_ROUTINE_CYCLES: u64 = CPU_FREQUENCY as u64;
+
+#[derive(Clone, Debug)]
+pub struct GbsHeader {
+    pub song_count: u8,
+    // Zero-based, the file stores it starting from 1
+    pub first_song: u8,
+    pub load_address: u16,
+    pub init_address: u16,
+    pub play_address: u16,
+    pub stack_pointer: u16,
+    pub timer_modulo: u8,
+    pub timer_control: u8,
+    pub title: String,
+    pub author: String,
+    pub copyright: String,
+}
+
+impl GbsHeader {
+    pub fn parse(data: &[u8]) -> Result<GbsHeader, GbsError> {
+        if data.len() < HEADER_SIZE {
+            return Err(GbsError::TooSmall(data.len()));
+        }
+        if &data[..SIGNATURE.len()] != SIGNATURE {
+            return Err(GbsError::InvalidSignature);
+        }
+        let version = data[VERSION_ADDRESS];
+        if version != SUPPORTED_VERSION {
+            return Err(GbsError::UnsupportedVersion(version));
+        }
+
+        let word = |address: usize| u16::from_le_bytes([data[address], data[address + 1]]);
+        let load_address = word(LOAD_ADDRESS);
+        if !(MIN_LOAD_ADDRESS..=MAX_LOAD_ADDRESS).contains(&load_address) {
+            return Err(GbsError::InvalidLoadAddress(load_address));
+        }
+
+        Ok(GbsHeader {
+            song_count: data[SONG_COUNT_ADDRESS],
+            first_song: data[FIRST_SONG_ADDRESS].saturating_sub(1),
+            load_address,
+            init_address: word(INIT_ADDRESS),
+            play_address: word(PLAY_ADDRESS),
+            stack_pointer: word(STACK_POINTER_ADDRESS),
+            timer_modulo: data[TIMER_MODULO_ADDRESS],
+            timer_control: data[TIMER_CONTROL_ADDRESS],
+            title: text_field(&data[TITLE_START..TITLE_START + TEXT_FIELD_LENGTH]),
+            author: text_field(&data[AUTHOR_START..AUTHOR_START + TEXT_FIELD_LENGTH]),
+            copyright: text_field(&data[COPYRIGHT_START..COPYRIGHT_START + TEXT_FIELD_LENGTH]),
+        })
+    }
+
+    // T-cycles between two calls of the play routine
+    pub fn play_period(&self) -> u32 {
+        if self.timer_control & TAC_TIMER_ENABLE == 0 {
+            return DOTS_PER_FRAME;
+        }
+        let cycles_per_tick = match self.timer_control & TAC_CLOCK_SELECT_MASK {
+            0b00 => 1024,
+            0b01 => 16,
+            0b10 => 64,
+            _ => 256,
+        };
+        let period = cycles_per_tick * (256 - self.timer_modulo as u32);
+        // We stay at normal speed, so the rate a double speed rip expects is kept by halving
+        if self.timer_control & TAC_DOUBLE_SPEED != 0 {
+            period / 2
+        } else {
+            period
+        }
+    }
+}
+
+fn text_field(bytes: &[u8]) -> String {
+    bytes
+        .iter()
+        .take_while(|&&byte| byte != 0x00)
+        .map(|&byte| byte as char)
+        .collect::<String>()
+        .trim_end()
+        .to_string()
+}
+
+#[derive(Debug)]
+pub enum GbsError {
+    Io(io::Error),
+    TooSmall(usize),
+    InvalidSignature,
+    UnsupportedVersion(u8),
+    InvalidLoadAddress(u16),
+    InvalidTrack { track: u8, song_count: u8 },
+    Cartridge(CartridgeError),
+}
+
+impl fmt::Display for GbsError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            GbsError::Io(error) => write!(f, "could not read GBS file: {}", error),
+            GbsError::TooSmall(size) => write!(
+                f,
+                "file is {} bytes, too small to contain a GBS header",
+                size
+            ),
+            GbsError::InvalidSignature => write!(f, "file does not start with the GBS signature"),
+            GbsError::UnsupportedVersion(version) => {
+                write!(f, "unsupported GBS version {}", version)
+            }
+            GbsError::InvalidLoadAddress(address) => {
+                write!(
+                    f,
+                    "load address 0x{:04x} is outside of 0x0400-0x7fff",
+                    address
+                )
+            }
+            GbsError::InvalidTrack { track, song_count } => write!(
+                f,
+                "track {} does not exist, the file has {} songs",
+                track + 1,
+                song_count
+            ),
+            GbsError::Cartridge(error) => write!(f, "could not map the GBS payload: {}", error),
+        }
+    }
+}
+
+impl std::error::Error for GbsError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            GbsError::Io(error) => Some(error),
+            GbsError::Cartridge(error) => Some(error),
+            _ => None,
+        }
+    }
+}
+
+impl From<io::Error> for GbsError {
+    fn from(error: io::Error) -> Self {
+        GbsError::Io(error)
+    }
+}
+
+impl From<CartridgeError> for GbsError {
+    fn from(error: CartridgeError) -> Self {
+        GbsError::Cartridge(error)
+    }
+}
+
+// Plays a `.gbs` music rip by running its init and play routines on the emulated
+// hardware with nothing but the payload mapped, the way a hardware GBS player does
+pub struct GbsPlayer {
+    pub header: GbsHeader,
+    rom: Vec<u8>,
+    cpu: CPU,
+    play_period: u32,
+    cycles_until_play: i64,
+    // T-cycles spent in the init or play routine that's currently running
+    routine_cycles: Option<u64>,
+}
+
+impl GbsPlayer {
+    pub fn from_file<P: AsRef<Path>>(path: P) -> Result<GbsPlayer, GbsError> {
+        GbsPlayer::from_bytes(&fs::read(path)?)
+    }
+
+    // Starts playing the track the header marks as the first one
+    pub fn from_bytes(data: &[u8]) -> Result<GbsPlayer, GbsError> {
+        let header = GbsHeader::parse(data)?;
+        let rom = build_rom(&header, &data[HEADER_SIZE..]);
+        let mut player = GbsPlayer {
+            play_period: header.play_period(),
+            cpu: CPU::new(MemoryBus::new(Cartridge::from_bytes(rom.clone())?)),
+            rom,
+            header,
+            cycles_until_play: 0,
+            routine_cycles: None,
+        };
+        player.start_track(player.header.first_song)?;
+        Ok(player)
+    }
+
+    // Resets the hardware and runs the init routine for the zero-based track
+    pub fn start_track(&mut self, track: u8) -> Result<(), GbsError> {
+        if track >= self.header.song_count {
+            return Err(GbsError::InvalidTrack {
+                track,
+                song_count: self.header.song_count,
+            });
+        }
+
+        let sample_rate = self.apu_mut().sample_rate();
+        self.cpu = CPU::new(MemoryBus::new(Cartridge::from_bytes(self.rom.clone())?));
+        self.apu_mut().set_sample_rate(sample_rate);
+
+        let bus = self.cpu.bus_mut();
+        bus.set_byte(RAM_ENABLE_REGISTER, RAM_ENABLE_VALUE);
+        bus.set_byte(ROM_BANK_REGISTER, 1);
+        // Sound on, every channel on both sides at full volume
+        bus.set_byte(NR52_REGISTER, 0x80);
+        bus.set_byte(NR51_REGISTER, 0xFF);
+        bus.set_byte(NR50_REGISTER, 0x77);
+        bus.set_byte(TMA_REGISTER, self.header.timer_modulo);
+        bus.set_byte(TAC_REGISTER, self.header.timer_control);
+
+        self.cpu.registers_mut().a = track;
+        self.call(self.header.init_address);
+        self.cycles_until_play = self.play_period as i64;
+        Ok(())
+    }
+
+    pub fn apu_mut(&mut self) -> &mut Apu {
+        &mut self.cpu.bus_mut().apu
+    }
+
+    // Runs one instruction of the current routine, or idles until play is due,
+    // and returns the T-cycles that took
+    pub fn step(&mut self) -> u32 {
+        if self.routine_cycles.is_some() && self.cpu.pc() == RETURN_ADDRESS {
+            self.routine_cycles = None;
+        }
+
+        let cycles = match self.routine_cycles {
+            Some(spent) if spent < MAX_ROUTINE_CYCLES => {
+                let cycles = self.cpu.step();
+                self.routine_cycles = Some(spent + cycles as u64);
+                cycles
+            }
+            _ if self.cycles_until_play <= 0 => {
+                self.cycles_until_play += self.play_period as i64;
+                self.call(self.header.play_address);
+                return 0;
+            }
+            _ => {
+                self.routine_cycles = None;
+                self.cpu.bus_mut().tick(T_CYCLES_PER_M_CYCLE);
+                T_CYCLES_PER_M_CYCLE
+            }
+        };
+        self.cycles_until_play -= cycles as i64;
+        cycles
+    }
+
+    pub fn run_for(&mut self, t_cycles: u64) {
+        let mut elapsed = 0;
+        while elapsed < t_cycles {
+            elapsed += self.step() as u64;
+        }
+    }
+
+    fn call(&mut self, address: u16) {
+        self.cpu
+            .call(address, self.header.stack_pointer, RETURN_ADDRESS);
+        self.routine_cycles = Some(0);
+    }
+}
+
+// Lays the payload out at its load address in a flat image with a valid MBC5 header,
+// rounded up to a power of two number of banks
+fn build_rom(header: &GbsHeader, payload: &[u8]) -> Vec<u8> {
+    let load_address = header.load_address as usize;
+    let size = (load_address + payload.len())
+        .next_power_of_two()
+        .max(MIN_ROM_SIZE);
+    let mut rom = vec![0xFF; size];
+    rom[load_address..load_address + payload.len()].copy_from_slice(payload);
+
+    for vector in RST_VECTORS {
+        let [low, high] = (header.load_address + vector).to_le_bytes();
+        rom[vector as usize..vector as usize + 3].copy_from_slice(&[JP_OPCODE, low, high]);
+    }
+
+    rom[LOGO_START..LOGO_START + NINTENDO_LOGO.len()].copy_from_slice(&NINTENDO_LOGO);
+    rom[CARTRIDGE_INFO_START..=CARTRIDGE_INFO_END].fill(0x00);
+    rom[CARTRIDGE_TYPE_ADDRESS] = CARTRIDGE_TYPE_MBC5_RAM;
+    rom[ROM_SIZE_ADDRESS] = (size / MIN_ROM_SIZE).trailing_zeros() as u8;
+    rom[RAM_SIZE_ADDRESS] = RAM_SIZE_8_KIB;
+    rom[HEADER_CHECKSUM_ADDRESS] = header_checksum(&rom);
+    rom
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn gbs_file(load_address: u16, init: u16, play: u16, payload: &[u8]) -> Vec<u8> {
+        let mut data = vec![0; HEADER_SIZE];
+        data[..SIGNATURE.len()].copy_from_slice(SIGNATURE);
+        data[VERSION_ADDRESS] = SUPPORTED_VERSION;
+        data[SONG_COUNT_ADDRESS] = 1;
+        data[FIRST_SONG_ADDRESS] = 1;
+        data[LOAD_ADDRESS..LOAD_ADDRESS + 2].copy_from_slice(&load_address.to_le_bytes());
+        data[INIT_ADDRESS..INIT_ADDRESS + 2].copy_from_slice(&init.to_le_bytes());
+        data[PLAY_ADDRESS..PLAY_ADDRESS + 2].copy_from_slice(&play.to_le_bytes());
+        data[STACK_POINTER_ADDRESS..STACK_POINTER_ADDRESS + 2]
+            .copy_from_slice(&0xFFFEu16.to_le_bytes());
+        data.extend_from_slice(payload);
+        data
+    }
+
+    #[test]
+    fn rst_goes_through_the_trampoline_to_the_payload() {
+        let mut payload = vec![0x00; 0x10];
+        // init: RST 08, then mark that it came back
+        payload[..6].copy_from_slice(&[0xCF, 0x3E, 0x99, 0xEA, 0x01, 0xC0]);
+        payload[6] = 0xC9;
+        // load + 0x08: LD A,0x42; LD (0xC000),A; RET
+        payload[0x08..0x0E].copy_from_slice(&[0x3E, 0x42, 0xEA, 0x00, 0xC0, 0xC9]);
+        payload.push(0xC9);
+        let mut player =
+            GbsPlayer::from_bytes(&gbs_file(0x0400, 0x0400, 0x0410, &payload)).unwrap();
+
+        player.run_for(1_000);
+        assert_eq!(player.cpu.bus().read_byte(0xC000), 0x42);
+        assert_eq!(player.cpu.bus().read_byte(0xC001), 0x99);
+        assert!(player.routine_cycles.is_none());
+    }
+
+    #[test]
+    fn play_routine_runs_jr_loops_and_sp_relative_loads() {
+        let mut payload = vec![0x00; 0x10];
+        // init: clear the loop counter at 0xC002
+        payload[..6].copy_from_slice(&[0x3E, 0x00, 0xEA, 0x02, 0xC0, 0xC9]);
+        payload.extend_from_slice(&[
+            // LD HL,SP-2, then store HL at 0xC000
+            0xF8, 0xFE, 0x7D, 0xEA, 0x00, 0xC0, 0x7C, 0xEA, 0x01, 0xC0,
+            // LD B,5, then count to 5 at 0xC002 in a JR NZ loop
+            0x06, 0x05, 0xFA, 0x02, 0xC0, 0x3C, 0xEA, 0x02, 0xC0, 0x05, 0x20, 0xF6, 0xC9,
+        ]);
+        let mut player =
+            GbsPlayer::from_bytes(&gbs_file(0x0400, 0x0400, 0x0410, &payload)).unwrap();
+
+        // Past the first call of the play routine but not the second
+        player.run_for(player.play_period as u64 + 2_000);
+        let bus = player.cpu.bus();
+        // The call to play left SP at 0xFFFC
+        assert_eq!(bus.read_byte(0xC000), 0xFA);
+        assert_eq!(bus.read_byte(0xC001), 0xFF);
+        assert_eq!(bus.read_byte(0xC002), 5);
+        assert!(player.routine_cycles.is_none());
+    }
+}
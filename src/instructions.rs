@@ -1,3 +1,5 @@
+use crate::registers::FlagRegister;
+
 pub enum Instruction {
     ADD(ArithmeticTarget),
     ADDL(ArithmeticTargetLong),
@@ -110,6 +112,19 @@ pub enum JumpType {
     Always,
 }
 
+impl JumpType {
+    // Shared by the branch instructions and the cycle table so both agree on whether a branch is taken
+    pub fn is_satisfied(&self, flags: &FlagRegister) -> bool {
+        match self {
+            JumpType::NotZero => !flags.zero,
+            JumpType::Zero => flags.zero,
+            JumpType::NotCarry => !flags.carry,
+            JumpType::Carry => flags.carry,
+            JumpType::Always => true,
+        }
+    }
+}
+
 pub enum StackRegisters {
     AF,
     BC,
@@ -125,6 +140,8 @@ pub enum LoadType {
     AFromByteAddress(ByteAddress),
     ByteAddressFromA(ByteAddress),
     SPToAddress,
+    // LD HL,SP+e8 with a signed offset
+    HLFromSPOffset,
 }
 
 pub enum LoadByteTarget {
@@ -187,6 +204,122 @@ impl Instruction {
         }
     }
 
+    // Duration in M-cycles (4 T-cycles each). Conditional control flow costs more when the
+    // branch is taken, so the flags are needed as they are before the instruction runs.
+    pub fn cycles(&self, flags: &FlagRegister) -> u8 {
+        match self {
+            Instruction::ADD(target)
+            | Instruction::ADC(target)
+            | Instruction::SUB(target)
+            | Instruction::SBC(target)
+            | Instruction::CMP(target)
+            | Instruction::AND(target)
+            | Instruction::OR(target)
+            | Instruction::XOR(target) => match target {
+                ArithmeticTarget::HLI | ArithmeticTarget::D8 => 2,
+                _ => 1,
+            },
+
+            Instruction::ADDL(ArithmeticTargetLong::S8) => 4,
+            Instruction::ADDL(_) => 2,
+
+            Instruction::INC(target) | Instruction::DEC(target) => match target {
+                IncDecTarget::HLI => 3,
+                IncDecTarget::BC | IncDecTarget::DE | IncDecTarget::HL | IncDecTarget::SP => 2,
+                _ => 1,
+            },
+
+            Instruction::RLCA | Instruction::RRCA | Instruction::RLA | Instruction::RRA => 1,
+
+            // Prefixed instructions pay one extra cycle for fetching the 0xCB prefix
+            Instruction::RLC(target)
+            | Instruction::RRC(target)
+            | Instruction::RL(target)
+            | Instruction::RR(target)
+            | Instruction::SLA(target)
+            | Instruction::SRA(target)
+            | Instruction::SWAP(target)
+            | Instruction::SRL(target)
+            | Instruction::RES(_, target)
+            | Instruction::SET(_, target) => match target {
+                RegisterTarget::HLI => 4,
+                _ => 2,
+            },
+            // BIT only reads (HL), so it skips the write back cycle
+            Instruction::BIT(_, target) => match target {
+                RegisterTarget::HLI => 3,
+                _ => 2,
+            },
+
+            Instruction::JP(test) => {
+                if test.is_satisfied(flags) {
+                    4
+                } else {
+                    3
+                }
+            }
+            Instruction::JPL => 1,
+            Instruction::JR(test) => {
+                if test.is_satisfied(flags) {
+                    3
+                } else {
+                    2
+                }
+            }
+            Instruction::CALL(test) => {
+                if test.is_satisfied(flags) {
+                    6
+                } else {
+                    3
+                }
+            }
+            // An unconditional RET doesn't spend a cycle evaluating a condition
+            Instruction::RET(JumpType::Always) => 4,
+            Instruction::RET(test) => {
+                if test.is_satisfied(flags) {
+                    5
+                } else {
+                    2
+                }
+            }
+            Instruction::RETI => 4,
+            Instruction::RST(_) => 4,
+
+            Instruction::PUSH(_) => 4,
+            Instruction::POP(_) => 3,
+
+            Instruction::LD(ld_type) => match ld_type {
+                LoadType::Byte(LoadByteTarget::HLI, LoadByteSource::D8) => 3,
+                LoadType::Byte(LoadByteTarget::HLI, _)
+                | LoadType::Byte(_, LoadByteSource::HLI)
+                | LoadType::Byte(_, LoadByteSource::D8) => 2,
+                LoadType::Byte(_, _) => 1,
+                LoadType::Word(_, LoadWordSource::D16) => 3,
+                LoadType::Word(_, _) => 2,
+                LoadType::AFromIndirect(_) | LoadType::IndirectFromA(_) => 2,
+                LoadType::AFromByteAddress(address) | LoadType::ByteAddressFromA(address) => {
+                    match address {
+                        ByteAddress::C => 2,
+                        ByteAddress::A8 => 3,
+                        ByteAddress::A16 => 4,
+                    }
+                }
+                LoadType::SPToAddress => 5,
+                LoadType::HLFromSPOffset => 3,
+            },
+
+            Instruction::CCF
+            | Instruction::SCF
+            | Instruction::DAA
+            | Instruction::CPL
+            | Instruction::HALT
+            | Instruction::STOP
+            | Instruction::DI
+            | Instruction::EI
+            | Instruction::NOP => 1,
+        }
+    }
+
     fn from_byte_prefixed(byte: u8) -> Option<Instruction> {
         match byte {
             // Rotate Left Carry Instructions
@@ -970,10 +1103,7 @@ impl Instruction {
             ))),
             0xF0 => Some(Instruction::LD(LoadType::AFromByteAddress(ByteAddress::A8))),
             0xF2 => Some(Instruction::LD(LoadType::AFromByteAddress(ByteAddress::C))),
-            0xF8 => Some(Instruction::LD(LoadType::Word(
-                LoadWordTarget::HL,
-                LoadWordSource::SP,
-            ))),
+            0xF8 => Some(Instruction::LD(LoadType::HLFromSPOffset)),
             0xF9 => Some(Instruction::LD(LoadType::Word(
                 LoadWordTarget::SP,
                 LoadWordSource::HL,
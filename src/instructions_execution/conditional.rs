@@ -28,15 +28,7 @@ pub fn cmp(
 }
 
 pub fn jump(registers: &mut Registers, pc: u16, bus: &MemoryBus, test: JumpType) -> u16 {
-    let jump_condition = match test {
-        JumpType::NotZero => !registers.f.zero,
-        JumpType::Zero => registers.f.zero,
-        JumpType::NotCarry => !registers.f.carry,
-        JumpType::Carry => registers.f.carry,
-        JumpType::Always => true,
-    };
-
-    if jump_condition {
+    if test.is_satisfied(&registers.f) {
         let least_significant_byte = bus.read_byte(pc + 1) as u16;
         let most_significant_byte = bus.read_byte(pc + 2) as u16;
         (most_significant_byte << 8) | least_significant_byte
@@ -50,17 +42,10 @@ pub fn jpl(registers: Registers) -> u16 {
 }
 
 pub fn jump_relative(registers: &mut Registers, pc: u16, bus: &MemoryBus, test: JumpType) -> u16 {
-    let jump_condition = match test {
-        JumpType::NotZero => !registers.f.zero,
-        JumpType::Zero => registers.f.zero,
-        JumpType::NotCarry => !registers.f.carry,
-        JumpType::Carry => registers.f.carry,
-        JumpType::Always => true,
-    };
-
-    if jump_condition {
-        let offset = bus.read_byte(pc + 1);
-        pc.wrapping_add(offset as u16)
+    if test.is_satisfied(&registers.f) {
+        // The offset is signed and counts from the end of the 2-byte instruction
+        let offset = bus.read_byte(pc.wrapping_add(1)) as i8;
+        pc.wrapping_add(2).wrapping_add(offset as u16)
     } else {
         pc.wrapping_add(2)
     }
@@ -73,15 +58,7 @@ pub fn call(
     test: JumpType,
     sp: &mut u16,
 ) -> u16 {
-    let jump_condition = match test {
-        JumpType::NotZero => !registers.f.zero,
-        JumpType::Zero => registers.f.zero,
-        JumpType::NotCarry => !registers.f.carry,
-        JumpType::Carry => registers.f.carry,
-        JumpType::Always => true,
-    };
-
-    if jump_condition {
+    if test.is_satisfied(&registers.f) {
         let least_significant_byte = bus.read_byte(pc + 1) as u16;
         let most_significant_byte = bus.read_byte(pc + 2) as u16;
         *sp = (*sp).wrapping_sub(1);
@@ -101,14 +78,7 @@ pub fn ret(
     test: JumpType,
     sp: &mut u16,
 ) -> u16 {
-    let jump_condition = match test {
-        JumpType::NotZero => !registers.f.zero,
-        JumpType::Zero => registers.f.zero,
-        JumpType::NotCarry => !registers.f.carry,
-        JumpType::Carry => registers.f.carry,
-        JumpType::Always => true,
-    };
-    if jump_condition {
+    if test.is_satisfied(&registers.f) {
         let lsb = bus.read_byte(*sp) as u16;
         *sp = (*sp).wrapping_add(1);
         let msb = bus.read_byte(*sp) as u16;
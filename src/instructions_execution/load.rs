@@ -141,6 +141,17 @@ pub fn load(
                 bus.set_byte(address + 1, (*sp >> 8) as u8);
                 pc.wrapping_add(3)
             }
+
+            // H and C come from adding the offset to the low byte of SP as if unsigned
+            LoadType::HLFromSPOffset => {
+                let offset = bus.read_byte(pc.wrapping_add(1));
+                registers.set_hl(sp.wrapping_add(offset as i8 as u16));
+                registers.f.zero = false;
+                registers.f.subtract = false;
+                registers.f.half_carry = (*sp & 0x0F) + (offset as u16 & 0x0F) > 0x0F;
+                registers.f.carry = (*sp & 0xFF) + offset as u16 > 0xFF;
+                pc.wrapping_add(2)
+            }
         }
     }
 }
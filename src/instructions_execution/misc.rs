@@ -1,4 +1,7 @@
-use crate::registers::{self, Registers};
+use crate::instructions_execution::stack;
+use crate::memory::MemoryBus;
+use crate::registers::Registers;
+use crate::timer::DIV_REGISTER;
 
 pub fn ccf(registers: &mut Registers, pc: u16) -> u16 {
     registers.f.carry = !registers.f.carry;
@@ -10,38 +13,138 @@ pub fn scf(registers: &mut Registers, pc: u16) -> u16 {
     pc.wrapping_add(1)
 }
 
+// Turns A back into packed BCD after an addition or subtraction of two BCD numbers,
+// using the flags that instruction left behind
 pub fn daa(registers: &mut Registers, pc: u16) -> u16 {
+    let mut adjust = 0;
+    let mut carry = registers.f.carry;
+    if registers.f.subtract {
+        if carry {
+            adjust |= 0x60;
+        }
+        if registers.f.half_carry {
+            adjust |= 0x06;
+        }
+        registers.a = registers.a.wrapping_sub(adjust);
+    } else {
+        if carry || registers.a > 0x99 {
+            adjust |= 0x60;
+            carry = true;
+        }
+        if registers.f.half_carry || registers.a & 0x0F > 0x09 {
+            adjust |= 0x06;
+        }
+        registers.a = registers.a.wrapping_add(adjust);
+    }
+    registers.f.zero = registers.a == 0;
+    registers.f.half_carry = false;
+    registers.f.carry = carry;
     pc.wrapping_add(1)
-} // TODO: Implement the daa function
+}
 
 pub fn cpl(registers: &mut Registers, pc: u16) -> u16 {
+    registers.a = !registers.a;
+    registers.f.subtract = true;
+    registers.f.half_carry = true;
     pc.wrapping_add(1)
-} // TODO: Implement the cpl function
+}
 
-pub fn reti(registers: &mut Registers, pc: u16) -> u16 {
-    pc.wrapping_add(1)
-} // TODO: Implement the reti function
+// Returns like RET, but turns IME back on straight away instead of after the next instruction like EI
+pub fn reti(bus: &mut MemoryBus, sp: &mut u16, ime: &mut bool) -> u16 {
+    *ime = true;
+    stack::pop_word(bus, sp)
+}
 
-pub fn rst(registers: &mut Registers, pc: u16, value: u8) -> u16 {
-    pc.wrapping_add(1)
-} // TODO: Implement the rst function
+// A one-byte CALL to one of the eight vectors at 0x00, 0x08, ... 0x38
+pub fn rst(bus: &mut MemoryBus, sp: &mut u16, pc: u16, vector: u8) -> u16 {
+    stack::push_word(bus, sp, pc.wrapping_add(1));
+    vector as u16 * 8
+}
 
 pub fn nop(pc: u16) -> u16 {
     pc.wrapping_add(1)
 } // TODO: Implement the nop function
 
-pub fn stop(registers: &mut Registers, pc: u16) -> u16 {
-    pc.wrapping_add(1)
-} // TODO: Implement the stop function
+// STOP is encoded as 0x10 0x00. It resets DIV, and on a CGB with a speed
+// switch armed through KEY1 it changes CPU speed instead of stopping.
+pub fn stop(bus: &mut MemoryBus, stopped: &mut bool, pc: u16) -> u16 {
+    bus.set_byte(DIV_REGISTER, 0);
+    if !bus.switch_speed() {
+        *stopped = true;
+    }
+    pc.wrapping_add(2)
+}
 
-pub fn halt(registers: &mut Registers, pc: u16) -> u16 {
+// HALT sleeps until an interrupt is pending. With IME off and an interrupt
+// already pending it doesn't sleep at all; instead the CPU fails to increment
+// PC after the next opcode fetch, so the following byte is read twice.
+pub fn halt(bus: &MemoryBus, ime: bool, halted: &mut bool, halt_bug: &mut bool, pc: u16) -> u16 {
+    if !ime && bus.pending_interrupts() != 0 {
+        *halt_bug = true;
+    } else {
+        *halted = true;
+    }
     pc.wrapping_add(1)
-} // TODO: Implement the halt function
+}
 
-pub fn di(registers: &mut Registers, pc: u16) -> u16 {
+// DI takes effect immediately and also cancels an EI still waiting to take effect
+pub fn di(ime: &mut bool, ime_scheduled: &mut bool, pc: u16) -> u16 {
+    *ime = false;
+    *ime_scheduled = false;
     pc.wrapping_add(1)
-} // TODO: Implement the di function
+}
 
-pub fn ei(registers: &mut Registers, pc: u16) -> u16 {
+// IME is only set after the instruction following EI, so `EI; RET` returns before any interrupt fires
+pub fn ei(ime_scheduled: &mut bool, pc: u16) -> u16 {
+    *ime_scheduled = true;
     pc.wrapping_add(1)
-} // TODO: Implement the ei function
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn daa_corrects_bcd_addition() {
+        // 0x15 + 0x27 as a plain binary addition gives 0x3C
+        let mut registers = Registers::new();
+        registers.a = 0x3C;
+        registers.f.subtract = false;
+        registers.f.half_carry = false;
+        registers.f.carry = false;
+        daa(&mut registers, 0);
+        assert_eq!(registers.a, 0x42);
+        assert!(!registers.f.carry);
+
+        // 0x99 + 0x01 gives 0x9A, which wraps around to 0x00 with a carry
+        registers.a = 0x9A;
+        daa(&mut registers, 0);
+        assert_eq!(registers.a, 0x00);
+        assert!(registers.f.zero);
+        assert!(registers.f.carry);
+    }
+
+    #[test]
+    fn daa_corrects_bcd_subtraction() {
+        // 0x42 - 0x15 as a plain binary subtraction gives 0x2D with a half borrow
+        let mut registers = Registers::new();
+        registers.a = 0x2D;
+        registers.f.subtract = true;
+        registers.f.half_carry = true;
+        registers.f.carry = false;
+        daa(&mut registers, 0);
+        assert_eq!(registers.a, 0x27);
+        assert!(registers.f.subtract);
+        assert!(!registers.f.carry);
+    }
+
+    #[test]
+    fn cpl_inverts_a() {
+        let mut registers = Registers::new();
+        registers.a = 0x35;
+        assert_eq!(cpl(&mut registers, 0x100), 0x101);
+        assert_eq!(registers.a, 0xCA);
+        assert!(registers.f.subtract);
+        assert!(registers.f.half_carry);
+    }
+}
@@ -5,12 +5,47 @@ pub fn push(
     target: StackRegisters,
     bus: &mut MemoryBus,
     pc: u16,
+    sp: &mut u16,
 ) -> u16 {
-    pc
+    let value = match target {
+        StackRegisters::AF => registers.get_af(),
+        StackRegisters::BC => registers.get_bc(),
+        StackRegisters::DE => registers.get_de(),
+        StackRegisters::HL => registers.get_hl(),
+    };
+    push_word(bus, sp, value);
+    pc.wrapping_add(1)
 }
 
-pub fn pop(registers: &mut Registers, target: StackRegisters, bus: &mut MemoryBus, pc: u16) -> u16 {
-    pc
+pub fn pop(
+    registers: &mut Registers,
+    target: StackRegisters,
+    bus: &mut MemoryBus,
+    pc: u16,
+    sp: &mut u16,
+) -> u16 {
+    let value = pop_word(bus, sp);
+    match target {
+        StackRegisters::AF => registers.set_af(value),
+        StackRegisters::BC => registers.set_bc(value),
+        StackRegisters::DE => registers.set_de(value),
+        StackRegisters::HL => registers.set_hl(value),
+    }
+    pc.wrapping_add(1)
+}
+
+// The stack grows downwards, with the high byte pushed first
+pub fn push_word(bus: &mut MemoryBus, sp: &mut u16, value: u16) {
+    *sp = (*sp).wrapping_sub(1);
+    bus.set_byte(*sp, ((value & 0xFF00) >> 8) as u8);
+    *sp = (*sp).wrapping_sub(1);
+    bus.set_byte(*sp, (value & 0x00FF) as u8);
 }
 
-// TODO: Implement the push and pop functions
+pub fn pop_word(bus: &MemoryBus, sp: &mut u16) -> u16 {
+    let lsb = bus.read_byte(*sp) as u16;
+    *sp = (*sp).wrapping_add(1);
+    let msb = bus.read_byte(*sp) as u16;
+    *sp = (*sp).wrapping_add(1);
+    (msb << 8) | lsb
+}
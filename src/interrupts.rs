@@ -0,0 +1,54 @@
+pub const INTERRUPT_FLAG_REGISTER: u16 = 0xFF0F;
+pub const INTERRUPT_ENABLE_REGISTER: u16 = 0xFFFF;
+
+// Only the lower five bits of IE and IF are wired to interrupt sources
+pub const INTERRUPT_MASK: u8 = 0x1F;
+
+// Pushing PC and jumping to the vector takes 5 M-cycles
+pub const INTERRUPT_DISPATCH_CYCLES: u8 = 5;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Interrupt {
+    VBlank,
+    LcdStat,
+    Timer,
+    Serial,
+    Joypad,
+}
+
+impl Interrupt {
+    // Lower bits win when several interrupts are pending at once
+    pub const PRIORITY_ORDER: [Interrupt; 5] = [
+        Interrupt::VBlank,
+        Interrupt::LcdStat,
+        Interrupt::Timer,
+        Interrupt::Serial,
+        Interrupt::Joypad,
+    ];
+
+    pub fn bit(&self) -> u8 {
+        match self {
+            Interrupt::VBlank => 1 << 0,
+            Interrupt::LcdStat => 1 << 1,
+            Interrupt::Timer => 1 << 2,
+            Interrupt::Serial => 1 << 3,
+            Interrupt::Joypad => 1 << 4,
+        }
+    }
+
+    pub fn vector(&self) -> u16 {
+        match self {
+            Interrupt::VBlank => 0x0040,
+            Interrupt::LcdStat => 0x0048,
+            Interrupt::Timer => 0x0050,
+            Interrupt::Serial => 0x0058,
+            Interrupt::Joypad => 0x0060,
+        }
+    }
+
+    pub fn highest_priority(pending: u8) -> Option<Interrupt> {
+        Interrupt::PRIORITY_ORDER
+            .into_iter()
+            .find(|interrupt| pending & interrupt.bit() != 0)
+    }
+}
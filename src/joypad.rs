@@ -0,0 +1,161 @@
+pub const JOYP_REGISTER: u16 = 0xFF00;
+
+// Writing 0 to one of these selects that half of the buttons
+const SELECT_DIRECTIONS: u8 = 0x10;
+const SELECT_ACTIONS: u8 = 0x20;
+const SELECT_MASK: u8 = SELECT_DIRECTIONS | SELECT_ACTIONS;
+const LINES_MASK: u8 = 0x0F;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Button {
+    Right,
+    Left,
+    Up,
+    Down,
+    A,
+    B,
+    Select,
+    Start,
+}
+
+impl Button {
+    // Each button shares one of the four input lines with a button from the other half
+    fn line(&self) -> u8 {
+        match self {
+            Button::Right | Button::A => 1 << 0,
+            Button::Left | Button::B => 1 << 1,
+            Button::Up | Button::Select => 1 << 2,
+            Button::Down | Button::Start => 1 << 3,
+        }
+    }
+
+    fn is_direction(&self) -> bool {
+        matches!(
+            self,
+            Button::Right | Button::Left | Button::Up | Button::Down
+        )
+    }
+}
+
+pub struct Joypad {
+    // Pressed buttons are set bits, on the line they drive
+    directions: u8,
+    actions: u8,
+    select: u8,
+}
+
+impl Joypad {
+    pub fn new() -> Joypad {
+        Joypad {
+            directions: 0,
+            actions: 0,
+            select: SELECT_MASK,
+        }
+    }
+
+    // The press and release functions and write return true when a line went from
+    // high to low, which is what requests the joypad interrupt
+    pub fn press(&mut self, button: Button) -> bool {
+        let before = self.lines();
+        if button.is_direction() {
+            self.directions |= button.line();
+        } else {
+            self.actions |= button.line();
+        }
+        falling_edge(before, self.lines())
+    }
+
+    pub fn release(&mut self, button: Button) -> bool {
+        let before = self.lines();
+        if button.is_direction() {
+            self.directions &= !button.line();
+        } else {
+            self.actions &= !button.line();
+        }
+        falling_edge(before, self.lines())
+    }
+
+    pub fn read(&self) -> u8 {
+        0xC0 | self.select | self.lines()
+    }
+
+    pub fn write(&mut self, value: u8) -> bool {
+        let before = self.lines();
+        self.select = value & SELECT_MASK;
+        falling_edge(before, self.lines())
+    }
+
+    // True while a pressed button in a selected half pulls its line low, which is what wakes the CPU from STOP
+    pub fn line_low(&self) -> bool {
+        self.lines() != LINES_MASK
+    }
+
+    // Lines are active low, so a pressed button in a selected half reads as 0
+    fn lines(&self) -> u8 {
+        let mut pressed = 0;
+        if self.select & SELECT_DIRECTIONS == 0 {
+            pressed |= self.directions;
+        }
+        if self.select & SELECT_ACTIONS == 0 {
+            pressed |= self.actions;
+        }
+        !pressed & LINES_MASK
+    }
+}
+
+fn falling_edge(before: u8, after: u8) -> bool {
+    before & !after != 0
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn line_is_only_low_for_a_selected_half() {
+        let mut joypad = Joypad::new();
+        joypad.press(Button::Start);
+        assert!(!joypad.line_low());
+
+        joypad.write(SELECT_MASK & !SELECT_DIRECTIONS);
+        assert!(!joypad.line_low());
+
+        joypad.write(SELECT_MASK & !SELECT_ACTIONS);
+        assert!(joypad.line_low());
+
+        joypad.release(Button::Start);
+        assert!(!joypad.line_low());
+    }
+
+    #[test]
+    fn read_shows_only_the_selected_halves() {
+        let mut joypad = Joypad::new();
+        joypad.press(Button::Right);
+        joypad.press(Button::B);
+
+        // Nothing selected: every line reads high
+        assert_eq!(joypad.read(), 0xFF);
+        joypad.write(SELECT_MASK & !SELECT_DIRECTIONS);
+        assert_eq!(joypad.read(), 0xEE);
+        joypad.write(SELECT_MASK & !SELECT_ACTIONS);
+        assert_eq!(joypad.read(), 0xDD);
+        // Both selected: the halves are combined on the shared lines
+        joypad.write(0x00);
+        assert_eq!(joypad.read(), 0xCC);
+    }
+
+    #[test]
+    fn interrupt_only_on_a_high_to_low_transition() {
+        let mut joypad = Joypad::new();
+        joypad.write(SELECT_MASK & !SELECT_ACTIONS);
+        // Buttons in an unselected half don't move any line
+        assert!(!joypad.press(Button::Up));
+        assert!(joypad.press(Button::A));
+        // Going back high never interrupts
+        assert!(!joypad.release(Button::A));
+
+        // Selecting a half with a button held pulls its line low too
+        assert!(joypad.write(SELECT_MASK & !SELECT_DIRECTIONS));
+        assert!(!joypad.release(Button::Up));
+    }
+}
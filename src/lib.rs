@@ -0,0 +1,27 @@
+// CPU and the instruction enums keep the names the opcode tables use
+#![allow(clippy::upper_case_acronyms)]
+// Components are built through their explicit constructors, never defaulted
+#![allow(clippy::new_without_default)]
+
+pub mod apu;
+pub mod cartridge;
+pub mod cpu;
+pub mod gameboy;
+pub mod gbs;
+// The opcode tables and their handlers keep their original style, which these lints flag
+#[allow(dead_code)]
+mod instructions;
+#[allow(non_snake_case, clippy::assign_op_pattern, clippy::manual_rotate)]
+mod instructions_execution;
+pub mod interrupts;
+pub mod joypad;
+pub mod memory;
+pub mod png;
+pub mod ppu;
+pub mod registers;
+pub mod serial;
+pub mod timer;
+pub mod wav;
+
+pub use gameboy::GameBoy;
+pub use joypad::Button;
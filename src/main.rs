@@ -1,9 +1,332 @@
-mod cpu;
-mod instructions;
-mod instructions_execution;
-mod memory;
-mod registers;
+use std::env;
+use std::error::Error;
+use std::process;
+
+use gb_em::apu::CPU_FREQUENCY;
+use gb_em::cartridge::rtc::RtcMode;
+use gb_em::gbs::GbsPlayer;
+use gb_em::png;
+use gb_em::ppu::{SCREEN_HEIGHT, SCREEN_WIDTH};
+use gb_em::serial::tcp::TcpLink;
+use gb_em::serial::CaptureDevice;
+use gb_em::wav::AudioRecorder;
+use gb_em::GameBoy;
+
+const USAGE: &str = "usage:
+  gb-em <rom> [options]     run a ROM headless
+  gb-em <file.gbs> [track] [seconds] [output.wav]
+
+options:
+  --frames N                stop after N frames (default 3600)
+  --until-pc ADDR           stop once PC reaches ADDR
+  --until-serial TEXT       stop once the serial output contains TEXT
+  --until-memory ADDR=VALUE stop once the byte at ADDR equals VALUE
+  --screenshot PATH         save the screen as a PNG when stopping
+  --registers               print the CPU registers when stopping
+  --link-listen ADDR        wait for another emulator to plug in a link cable
+  --link-connect ADDR       plug a link cable into an emulator listening at ADDR
+
+Addresses and values are hex. With any --until option the exit status is 0 when
+it was hit and 1 when the frame limit ran out first. Errors, including the CPU
+locking up on an invalid opcode, exit with 2. A link cable replaces the serial
+capture, so it can't be used with --until-serial.";
+
+const EXIT_SUCCESS: i32 = 0;
+const EXIT_FRAME_LIMIT: i32 = 1;
+const EXIT_ERROR: i32 = 2;
+
+// A minute of emulated time, enough for the usual test ROMs to report back
+const DEFAULT_FRAME_LIMIT: u64 = 3600;
+const DEFAULT_GBS_SECONDS: u64 = 120;
 
 fn main() {
-    println!("Hello, world!");
+    let args: Vec<String> = env::args().skip(1).collect();
+    let status = match args.first() {
+        Some(path) if path.ends_with(".gbs") => match render_gbs(&args) {
+            Ok(()) => EXIT_SUCCESS,
+            Err(error) => {
+                eprintln!("{}", error);
+                EXIT_ERROR
+            }
+        },
+        Some(_) => match RunOptions::parse(&args) {
+            Ok(options) => match run(&options) {
+                Ok(status) => status,
+                Err(error) => {
+                    eprintln!("{}", error);
+                    EXIT_ERROR
+                }
+            },
+            Err(error) => {
+                eprintln!("{}\n\n{}", error, USAGE);
+                EXIT_ERROR
+            }
+        },
+        None => {
+            eprintln!("{}", USAGE);
+            EXIT_ERROR
+        }
+    };
+    process::exit(status);
+}
+
+struct RunOptions {
+    rom: String,
+    frames: u64,
+    until_pc: Option<u16>,
+    until_serial: Option<String>,
+    until_memory: Option<(u16, u8)>,
+    screenshot: Option<String>,
+    dump_registers: bool,
+    link: Option<Link>,
+}
+
+enum Link {
+    Listen(String),
+    Connect(String),
+}
+
+impl RunOptions {
+    fn parse(args: &[String]) -> Result<RunOptions, String> {
+        let mut options = RunOptions {
+            rom: args[0].clone(),
+            frames: DEFAULT_FRAME_LIMIT,
+            until_pc: None,
+            until_serial: None,
+            until_memory: None,
+            screenshot: None,
+            dump_registers: false,
+            link: None,
+        };
+
+        let mut args = args[1..].iter();
+        while let Some(flag) = args.next() {
+            let mut value = || args.next().ok_or_else(|| format!("{} needs a value", flag));
+            match flag.as_str() {
+                "--frames" => {
+                    let frames = value()?;
+                    options.frames = frames
+                        .parse()
+                        .map_err(|_| format!("invalid frame count '{}'", frames))?;
+                }
+                "--until-pc" => options.until_pc = Some(parse_hex(value()?)?),
+                "--until-serial" => options.until_serial = Some(value()?.clone()),
+                "--until-memory" => {
+                    let condition = value()?;
+                    let (address, byte) = condition
+                        .split_once('=')
+                        .ok_or_else(|| format!("expected ADDR=VALUE, got '{}'", condition))?;
+                    let byte = u8::try_from(parse_hex(byte)?)
+                        .map_err(|_| format!("'{}' doesn't fit in a byte", byte))?;
+                    options.until_memory = Some((parse_hex(address)?, byte));
+                }
+                "--screenshot" => options.screenshot = Some(value()?.clone()),
+                "--registers" => options.dump_registers = true,
+                "--link-listen" => options.link = Some(Link::Listen(value()?.clone())),
+                "--link-connect" => options.link = Some(Link::Connect(value()?.clone())),
+                _ => return Err(format!("unknown option '{}'", flag)),
+            }
+        }
+        if options.link.is_some() && options.until_serial.is_some() {
+            return Err("--until-serial can't be used with a link cable".to_string());
+        }
+        Ok(options)
+    }
+
+    fn has_exit_condition(&self) -> bool {
+        self.until_pc.is_some() || self.until_serial.is_some() || self.until_memory.is_some()
+    }
+}
+
+// Accepts 0x1234, $1234 and plain 1234
+fn parse_hex(text: &str) -> Result<u16, String> {
+    let digits = text
+        .strip_prefix("0x")
+        .or_else(|| text.strip_prefix("0X"))
+        .or_else(|| text.strip_prefix('$'))
+        .unwrap_or(text);
+    u16::from_str_radix(digits, 16).map_err(|_| format!("invalid hex number '{}'", text))
+}
+
+// Runs a ROM without any window or sound until one of the exit conditions is hit or
+// the frame limit runs out, then reports the state. Returns the exit status.
+fn run(options: &RunOptions) -> Result<i32, Box<dyn Error>> {
+    let mut gameboy = GameBoy::load_rom(&options.rom)?;
+    // Runs have to come out the same every time, whatever the host clock says
+    gameboy.set_rtc_mode(RtcMode::EmulatedCycles);
+    let serial = CaptureDevice::new();
+    match &options.link {
+        Some(Link::Listen(address)) => {
+            eprintln!("waiting for a link cable on {}", address);
+            gameboy.connect_serial(Box::new(TcpLink::listen(address.as_str())?));
+        }
+        Some(Link::Connect(address)) => {
+            gameboy.connect_serial(Box::new(TcpLink::connect(address.as_str())?));
+        }
+        None => gameboy.connect_serial(Box::new(serial.clone())),
+    }
+
+    let mut reason = None;
+    let mut locked_up = false;
+    let mut frames = 0;
+    while frames < options.frames && reason.is_none() {
+        let stopped = gameboy.run_frame_until(|cpu| {
+            cpu.locked_up().is_some()
+                || options.until_pc == Some(cpu.pc())
+                || options
+                    .until_memory
+                    .is_some_and(|(address, value)| cpu.bus().read_byte(address) == value)
+        });
+        frames += 1;
+
+        if stopped {
+            let cpu = gameboy.cpu();
+            reason = Some(if let Some(opcode) = cpu.locked_up() {
+                locked_up = true;
+                format!(
+                    "CPU locked up on invalid opcode 0x{:02x} at 0x{:04x}",
+                    opcode,
+                    cpu.pc()
+                )
+            } else if options.until_pc == Some(cpu.pc()) {
+                format!("reached PC 0x{:04x}", cpu.pc())
+            } else {
+                "memory condition met".to_string()
+            });
+        } else if let Some(text) = &options.until_serial {
+            if serial.text().contains(text.as_str()) {
+                reason = Some("serial output matched".to_string());
+            }
+        }
+    }
+
+    let output = serial.text();
+    if !output.is_empty() {
+        print!("{}", output);
+        if !output.ends_with('\n') {
+            println!();
+        }
+    }
+
+    let cpu = gameboy.cpu();
+    eprintln!(
+        "{} after {} frames ({} cycles)",
+        reason.as_deref().unwrap_or("stopped"),
+        frames,
+        cpu.cycles()
+    );
+    if options.dump_registers {
+        let registers = cpu.registers();
+        println!(
+            "AF={:04X} BC={:04X} DE={:04X} HL={:04X} SP={:04X} PC={:04X}",
+            registers.get_af(),
+            registers.get_bc(),
+            registers.get_de(),
+            registers.get_hl(),
+            cpu.sp(),
+            cpu.pc()
+        );
+    }
+    if let Some(path) = &options.screenshot {
+        png::save_png(
+            path,
+            SCREEN_WIDTH as u32,
+            SCREEN_HEIGHT as u32,
+            &gameboy.framebuffer_rgba(),
+        )?;
+    }
+
+    gameboy.flush()?;
+
+    Ok(match reason {
+        _ if locked_up => EXIT_ERROR,
+        None if options.has_exit_condition() => EXIT_FRAME_LIMIT,
+        _ => EXIT_SUCCESS,
+    })
+}
+
+// Renders one track of a GBS rip to a WAV file: gb-em <file.gbs> [track] [seconds] [output.wav]
+fn render_gbs(args: &[String]) -> Result<(), Box<dyn Error>> {
+    let path = &args[0];
+    let mut player = GbsPlayer::from_file(path)?;
+    // Tracks are numbered from 1 on the command line, like players show them
+    if let Some(track) = args.get(1) {
+        let track: u8 = track.parse()?;
+        player.start_track(track.saturating_sub(1))?;
+    }
+    let seconds = match args.get(2) {
+        Some(seconds) => seconds.parse()?,
+        None => DEFAULT_GBS_SECONDS,
+    };
+    let output = match args.get(3) {
+        Some(output) => output.clone(),
+        None => format!("{}.wav", path.trim_end_matches(".gbs")),
+    };
+
+    eprintln!(
+        "{} - {} ({})",
+        player.header.title, player.header.author, player.header.copyright
+    );
+    let sample_rate = player.apu_mut().sample_rate();
+    let mut recorder = AudioRecorder::create(&output, sample_rate, false)?;
+    for _ in 0..seconds {
+        player.run_for(CPU_FREQUENCY as u64);
+        recorder.record(&player.apu_mut().take_samples(), None)?;
+    }
+    recorder.finish()?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn args(text: &str) -> Vec<String> {
+        text.split_whitespace().map(str::to_string).collect()
+    }
+
+    #[test]
+    fn parse_hex_accepts_every_prefix() {
+        assert_eq!(parse_hex("0x1234"), Ok(0x1234));
+        assert_eq!(parse_hex("0XFF"), Ok(0xFF));
+        assert_eq!(parse_hex("$c000"), Ok(0xC000));
+        assert_eq!(parse_hex("150"), Ok(0x150));
+        assert!(parse_hex("0x").is_err());
+        assert!(parse_hex("10000").is_err());
+        assert!(parse_hex("xyz").is_err());
+    }
+
+    #[test]
+    fn parses_run_options() {
+        let options = RunOptions::parse(&args(
+            "game.gb --frames 10 --until-pc 0x150 --until-memory $a000=80 --screenshot out.png --registers",
+        ))
+        .unwrap();
+        assert_eq!(options.rom, "game.gb");
+        assert_eq!(options.frames, 10);
+        assert_eq!(options.until_pc, Some(0x150));
+        assert_eq!(options.until_memory, Some((0xA000, 0x80)));
+        assert_eq!(options.screenshot.as_deref(), Some("out.png"));
+        assert!(options.dump_registers);
+        assert!(options.has_exit_condition());
+
+        let options = RunOptions::parse(&args("game.gb")).unwrap();
+        assert_eq!(options.frames, DEFAULT_FRAME_LIMIT);
+        assert!(!options.has_exit_condition());
+        assert!(options.link.is_none());
+    }
+
+    #[test]
+    fn rejects_bad_run_options() {
+        for text in [
+            "game.gb --frames",
+            "game.gb --frames ten",
+            "game.gb --until-memory a000",
+            "game.gb --until-memory a000=100",
+            "game.gb --unknown",
+            "game.gb --until-serial ok --link-connect localhost:8765",
+        ] {
+            assert!(RunOptions::parse(&args(text)).is_err(), "{}", text);
+        }
+    }
 }
@@ -1,12 +1,391 @@
+use crate::apu::{Apu, NR10_REGISTER, NR52_REGISTER, WAVE_RAM_END, WAVE_RAM_START};
+use crate::cartridge::header::CgbSupport;
+use crate::cartridge::Cartridge;
+use crate::interrupts::{
+    Interrupt, INTERRUPT_ENABLE_REGISTER, INTERRUPT_FLAG_REGISTER, INTERRUPT_MASK,
+};
+use crate::joypad::{Button, Joypad, JOYP_REGISTER};
+use crate::ppu::{Ppu, PpuBackend, BGP_REGISTER, LCDC_REGISTER, LYC_REGISTER, WX_REGISTER};
+use crate::serial::{Serial, SB_REGISTER, SC_REGISTER};
+use crate::timer::{Timer, DIV_REGISTER, TAC_REGISTER};
+
+const ROM_BANK_0_START: u16 = 0x0000;
+const ROM_BANK_N_END: u16 = 0x7FFF;
+const VRAM_START: u16 = 0x8000;
+const VRAM_END: u16 = 0x9FFF;
+const EXTERNAL_RAM_START: u16 = 0xA000;
+const EXTERNAL_RAM_END: u16 = 0xBFFF;
+const WRAM_START: u16 = 0xC000;
+const WRAM_END: u16 = 0xDFFF;
+const ECHO_RAM_START: u16 = 0xE000;
+const ECHO_RAM_END: u16 = 0xFDFF;
+const OAM_START: u16 = 0xFE00;
+const OAM_END: u16 = 0xFE9F;
+const UNUSABLE_START: u16 = 0xFEA0;
+const UNUSABLE_END: u16 = 0xFEFF;
+const IO_START: u16 = 0xFF00;
+const IO_END: u16 = 0xFF7F;
+const HRAM_START: u16 = 0xFF80;
+const HRAM_END: u16 = 0xFFFE;
+
+pub const T_CYCLES_PER_M_CYCLE: u32 = 4;
+
+// Writing XX here copies XX00-XX9F into OAM
+pub const DMA_REGISTER: u16 = 0xFF46;
+// CGB speed switch control
+const KEY1_REGISTER: u16 = 0xFF4D;
+
+const WRAM_SIZE: usize = (WRAM_END - WRAM_START + 1) as usize;
+const IO_SIZE: usize = (IO_END - IO_START + 1) as usize;
+const HRAM_SIZE: usize = (HRAM_END - HRAM_START + 1) as usize;
+
+// One byte is copied per M-cycle
+const DMA_LENGTH: u16 = OAM_END - OAM_START + 1;
+// The copy starts one M-cycle after the write to DMA
+const DMA_START_DELAY: u8 = 1;
+
 pub struct MemoryBus {
-    memory: [u8; 0xFFFF],
+    pub cartridge: Cartridge,
+    wram: [u8; WRAM_SIZE],
+    io: [u8; IO_SIZE],
+    hram: [u8; HRAM_SIZE],
+    pub timer: Timer,
+    pub ppu: Ppu,
+    joypad: Joypad,
+    pub serial: Serial,
+    pub apu: Apu,
+    interrupt_enable: u8,
+    interrupt_flag: u8,
+    // Only CGB cartridges can use CGB features like the double speed mode
+    cgb_mode: bool,
+    speed_switch_armed: bool,
+    double_speed: bool,
+    // Last value written to DMA, which is also what it reads back as
+    dma_register: u8,
+    dma_source: u16,
+    // Bytes copied so far, None when no transfer is running
+    dma_progress: Option<u16>,
+    dma_delay: u8,
+    // Byte on the bus during the transfer; a CPU read outside HRAM sees it instead
+    dma_bus_value: u8,
 }
 
 impl MemoryBus {
+    pub fn new(cartridge: Cartridge) -> MemoryBus {
+        MemoryBus::with_ppu_backend(cartridge, PpuBackend::Scanline)
+    }
+
+    pub fn with_ppu_backend(cartridge: Cartridge, ppu_backend: PpuBackend) -> MemoryBus {
+        let cgb_mode = cartridge.header.cgb_support != CgbSupport::None;
+        MemoryBus {
+            cartridge,
+            wram: [0; WRAM_SIZE],
+            io: [0; IO_SIZE],
+            hram: [0; HRAM_SIZE],
+            timer: Timer::new(),
+            ppu: Ppu::new(ppu_backend),
+            joypad: Joypad::new(),
+            serial: Serial::new(),
+            apu: Apu::new(),
+            interrupt_enable: 0,
+            interrupt_flag: 0,
+            cgb_mode,
+            speed_switch_armed: false,
+            double_speed: false,
+            dma_register: 0xFF,
+            dma_source: 0,
+            dma_progress: None,
+            dma_delay: 0,
+            dma_bus_value: 0xFF,
+        }
+    }
+
+    // Performs the speed switch armed through KEY1, as triggered by STOP.
+    // Returns false when there was nothing to switch.
+    pub fn switch_speed(&mut self) -> bool {
+        if !self.cgb_mode || !self.speed_switch_armed {
+            return false;
+        }
+        self.speed_switch_armed = false;
+        self.double_speed = !self.double_speed;
+        true
+    }
+
+    pub fn double_speed(&self) -> bool {
+        self.double_speed
+    }
+
+    // Steps every component clocked alongside the CPU
+    pub fn tick(&mut self, t_cycles: u32) {
+        for _ in 0..t_cycles / T_CYCLES_PER_M_CYCLE {
+            self.step_dma();
+        }
+        if self.timer.tick(t_cycles) {
+            self.request_interrupt(Interrupt::Timer);
+        }
+        if self.serial.tick(t_cycles) {
+            self.request_interrupt(Interrupt::Serial);
+        }
+        // The LCD, the APU and the cartridge clock keep their own pace, so they see half as
+        // many cycles in double speed mode
+        let dots = if self.double_speed {
+            t_cycles / 2
+        } else {
+            t_cycles
+        };
+        self.cartridge.tick(dots);
+        self.interrupt_flag |= self.ppu.tick(dots);
+        self.apu.tick(dots);
+    }
+
+    pub fn press_button(&mut self, button: Button) {
+        if self.joypad.press(button) {
+            self.request_interrupt(Interrupt::Joypad);
+        }
+    }
+
+    pub fn release_button(&mut self, button: Button) {
+        if self.joypad.release(button) {
+            self.request_interrupt(Interrupt::Joypad);
+        }
+    }
+
+    pub fn joypad_line_low(&self) -> bool {
+        self.joypad.line_low()
+    }
+
+    pub fn dma_active(&self) -> bool {
+        self.dma_progress.is_some()
+    }
+
+    fn start_dma(&mut self, value: u8) {
+        self.dma_register = value;
+        // Sources past WRAM land on the echo area, which mirrors WRAM
+        let source = (value as u16) << 8;
+        self.dma_source = if source >= ECHO_RAM_START {
+            source - (ECHO_RAM_START - WRAM_START)
+        } else {
+            source
+        };
+        self.dma_progress = Some(0);
+        self.dma_delay = DMA_START_DELAY;
+    }
+
+    fn step_dma(&mut self) {
+        let Some(progress) = self.dma_progress else {
+            return;
+        };
+        if self.dma_delay > 0 {
+            self.dma_delay -= 1;
+            return;
+        }
+        let value = self.read_mapped(self.dma_source + progress);
+        self.ppu.write_oam(OAM_START + progress, value);
+        self.dma_bus_value = value;
+        self.dma_progress = (progress + 1 < DMA_LENGTH).then_some(progress + 1);
+    }
+
+    // While the DMA owns the bus the CPU can still reach everything from 0xFF00 up,
+    // since HRAM and the IO registers sit on their own internal bus
+    fn dma_blocks(&self, address: u16) -> bool {
+        self.dma_active() && self.dma_delay == 0 && address < IO_START
+    }
+
+    pub fn request_interrupt(&mut self, interrupt: Interrupt) {
+        self.interrupt_flag |= interrupt.bit();
+    }
+
+    pub fn interrupt_requested(&self, interrupt: Interrupt) -> bool {
+        self.interrupt_flag & interrupt.bit() != 0
+    }
+
+    pub fn clear_interrupt(&mut self, interrupt: Interrupt) {
+        self.interrupt_flag &= !interrupt.bit();
+    }
+
+    // Interrupts that are both requested and enabled, regardless of IME
+    pub fn pending_interrupts(&self) -> u8 {
+        self.interrupt_enable & self.interrupt_flag & INTERRUPT_MASK
+    }
+
     pub fn read_byte(&self, address: u16) -> u8 {
-        self.memory[address as usize]
+        if self.dma_blocks(address) {
+            // OAM is busy being written, anything else reads whatever the DMA is moving
+            return match address {
+                OAM_START..=UNUSABLE_END => 0xFF,
+                _ => self.dma_bus_value,
+            };
+        }
+        self.read_mapped(address)
+    }
+
+    fn read_mapped(&self, address: u16) -> u8 {
+        match address {
+            ROM_BANK_0_START..=ROM_BANK_N_END => self.cartridge.read_rom(address),
+            VRAM_START..=VRAM_END => self.ppu.read_vram(address),
+            EXTERNAL_RAM_START..=EXTERNAL_RAM_END => self.cartridge.read_ram(address),
+            WRAM_START..=WRAM_END => self.wram[(address - WRAM_START) as usize],
+            // Echo RAM mirrors the first 7.5 KiB of WRAM
+            ECHO_RAM_START..=ECHO_RAM_END => self.wram[(address - ECHO_RAM_START) as usize],
+            OAM_START..=OAM_END => self.ppu.read_oam(address),
+            // Prohibited area; the DMG reads it back as zeroes
+            UNUSABLE_START..=UNUSABLE_END => 0x00,
+            IO_START..=IO_END => self.read_io(address),
+            HRAM_START..=HRAM_END => self.hram[(address - HRAM_START) as usize],
+            INTERRUPT_ENABLE_REGISTER => self.interrupt_enable,
+        }
+    }
+
+    fn read_io(&self, address: u16) -> u8 {
+        match address {
+            JOYP_REGISTER => self.joypad.read(),
+            SB_REGISTER..=SC_REGISTER => self.serial.read(address),
+            DIV_REGISTER..=TAC_REGISTER => self.timer.read(address),
+            NR10_REGISTER..=NR52_REGISTER | WAVE_RAM_START..=WAVE_RAM_END => self.apu.read(address),
+            // The unused upper bits of IF always read back as 1
+            INTERRUPT_FLAG_REGISTER => self.interrupt_flag | !INTERRUPT_MASK,
+            DMA_REGISTER => self.dma_register,
+            LCDC_REGISTER..=LYC_REGISTER | BGP_REGISTER..=WX_REGISTER => {
+                self.ppu.read_register(address)
+            }
+            KEY1_REGISTER if self.cgb_mode => {
+                (self.double_speed as u8) << 7 | 0x7E | self.speed_switch_armed as u8
+            }
+            KEY1_REGISTER => 0xFF,
+            _ => self.io[(address - IO_START) as usize],
+        }
     }
+
     pub fn set_byte(&mut self, address: u16, value: u8) {
-        self.memory[address as usize] = value;
+        if self.dma_blocks(address) {
+            return;
+        }
+        match address {
+            ROM_BANK_0_START..=ROM_BANK_N_END => self.cartridge.write_rom(address, value),
+            VRAM_START..=VRAM_END => self.ppu.write_vram(address, value),
+            EXTERNAL_RAM_START..=EXTERNAL_RAM_END => self.cartridge.write_ram(address, value),
+            WRAM_START..=WRAM_END => self.wram[(address - WRAM_START) as usize] = value,
+            ECHO_RAM_START..=ECHO_RAM_END => self.wram[(address - ECHO_RAM_START) as usize] = value,
+            OAM_START..=OAM_END => self.ppu.write_oam(address, value),
+            UNUSABLE_START..=UNUSABLE_END => {}
+            IO_START..=IO_END => self.write_io(address, value),
+            HRAM_START..=HRAM_END => self.hram[(address - HRAM_START) as usize] = value,
+            INTERRUPT_ENABLE_REGISTER => self.interrupt_enable = value,
+        }
+    }
+
+    fn write_io(&mut self, address: u16, value: u8) {
+        match address {
+            JOYP_REGISTER => {
+                if self.joypad.write(value) {
+                    self.request_interrupt(Interrupt::Joypad);
+                }
+            }
+            SB_REGISTER..=SC_REGISTER => self.serial.write(address, value),
+            DIV_REGISTER..=TAC_REGISTER => self.timer.write(address, value),
+            NR10_REGISTER..=NR52_REGISTER | WAVE_RAM_START..=WAVE_RAM_END => {
+                self.apu.write(address, value)
+            }
+            INTERRUPT_FLAG_REGISTER => self.interrupt_flag = value & INTERRUPT_MASK,
+            DMA_REGISTER => self.start_dma(value),
+            LCDC_REGISTER..=LYC_REGISTER | BGP_REGISTER..=WX_REGISTER => {
+                self.ppu.write_register(address, value)
+            }
+            KEY1_REGISTER => self.speed_switch_armed = value & 0x01 != 0,
+            _ => self.io[(address - IO_START) as usize] = value,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::cartridge::header::test_rom;
+
+    fn bus_with_wram_pattern() -> MemoryBus {
+        let cartridge = Cartridge::from_bytes(test_rom::with_program(&[])).unwrap();
+        let mut bus = MemoryBus::new(cartridge);
+        for offset in 0..DMA_LENGTH {
+            bus.set_byte(WRAM_START + offset, offset as u8 ^ 0x5A);
+        }
+        bus
+    }
+
+    fn step_m_cycles(bus: &mut MemoryBus, m_cycles: u16) {
+        for _ in 0..m_cycles {
+            bus.tick(T_CYCLES_PER_M_CYCLE);
+        }
+    }
+
+    #[test]
+    fn dma_copies_one_byte_per_m_cycle() {
+        let mut bus = bus_with_wram_pattern();
+        bus.set_byte(DMA_REGISTER, 0xC0);
+        step_m_cycles(&mut bus, DMA_START_DELAY as u16);
+        for _ in 0..DMA_LENGTH {
+            assert!(bus.dma_active());
+            step_m_cycles(&mut bus, 1);
+        }
+        assert!(!bus.dma_active());
+        for offset in 0..DMA_LENGTH {
+            assert_eq!(bus.read_byte(OAM_START + offset), offset as u8 ^ 0x5A);
+        }
+    }
+
+    #[test]
+    fn cpu_reads_the_dma_bus_value_outside_hram() {
+        let mut bus = bus_with_wram_pattern();
+        bus.set_byte(HRAM_START, 0x12);
+        bus.set_byte(DMA_REGISTER, 0xC0);
+        // Nothing is blocked until the copy actually starts
+        assert_eq!(bus.read_byte(WRAM_START + 1), 1 ^ 0x5A);
+
+        step_m_cycles(&mut bus, DMA_START_DELAY as u16 + 3);
+        let moving = 2 ^ 0x5A;
+        assert_eq!(bus.read_byte(ROM_BANK_0_START), moving);
+        assert_eq!(bus.read_byte(WRAM_START + 0x100), moving);
+        assert_eq!(bus.read_byte(OAM_START), 0xFF);
+        // Writes outside HRAM are dropped
+        bus.set_byte(WRAM_START + 0x100, 0x34);
+
+        // HRAM and the registers stay reachable
+        assert_eq!(bus.read_byte(HRAM_START), 0x12);
+        bus.set_byte(HRAM_START, 0x56);
+        assert_eq!(bus.read_byte(HRAM_START), 0x56);
+        assert_eq!(bus.read_byte(DMA_REGISTER), 0xC0);
+
+        step_m_cycles(&mut bus, DMA_LENGTH);
+        assert!(!bus.dma_active());
+        assert_eq!(bus.read_byte(WRAM_START + 0x100), 0x00);
+    }
+
+    #[test]
+    fn dma_past_the_echo_area_still_reads_wram() {
+        let mut bus = bus_with_wram_pattern();
+        // 0xFE00 isn't echo RAM on the CPU side, but the DMA sees WRAM at 0xDE00
+        for offset in 0..DMA_LENGTH {
+            bus.set_byte(0xDE00 + offset, offset as u8);
+        }
+        bus.set_byte(DMA_REGISTER, 0xFE);
+        step_m_cycles(&mut bus, DMA_START_DELAY as u16 + DMA_LENGTH);
+        assert!(!bus.dma_active());
+        for offset in 0..DMA_LENGTH {
+            assert_eq!(bus.read_byte(OAM_START + offset), offset as u8);
+        }
+    }
+
+    #[test]
+    fn unused_if_bits_read_back_as_1() {
+        let mut bus = bus_with_wram_pattern();
+        assert_eq!(bus.read_byte(INTERRUPT_FLAG_REGISTER), 0xE0);
+        bus.set_byte(INTERRUPT_FLAG_REGISTER, 0xFF);
+        assert_eq!(bus.read_byte(INTERRUPT_FLAG_REGISTER), 0xFF);
+        assert_eq!(bus.pending_interrupts(), 0);
+        bus.set_byte(INTERRUPT_FLAG_REGISTER, Interrupt::Joypad.bit());
+        assert_eq!(bus.read_byte(INTERRUPT_FLAG_REGISTER), 0xF0);
+        // IE has no unused bits and keeps whatever is written
+        bus.set_byte(INTERRUPT_ENABLE_REGISTER, 0xE1);
+        assert_eq!(bus.read_byte(INTERRUPT_ENABLE_REGISTER), 0xE1);
+        assert_eq!(bus.pending_interrupts(), 0);
     }
 }
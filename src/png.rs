@@ -0,0 +1,151 @@
+use std::fs::File;
+use std::io::{self, BufWriter, Write};
+use std::path::Path;
+
+const SIGNATURE: [u8; 8] = [0x89, b'P', b'N', b'G', b'\r', b'\n', 0x1A, b'\n'];
+const BIT_DEPTH: u8 = 8;
+const COLOR_TYPE_RGBA: u8 = 6;
+const BYTES_PER_PIXEL: usize = 4;
+// Each scanline starts with its filter type, and we never filter
+const FILTER_NONE: u8 = 0;
+
+// zlib header for deflate with a 32 KiB window and no preset dictionary
+const ZLIB_HEADER: [u8; 2] = [0x78, 0x01];
+// Stored deflate blocks can't be longer than this
+const MAX_STORED_BLOCK: usize = 0xFFFF;
+const ADLER_MODULUS: u32 = 65521;
+
+// Writes 8-bit RGBA pixels as an uncompressed PNG. Game Boy screens are tiny, so
+// skipping compression keeps this simple without making the files unreasonably big.
+pub fn write_png<W: Write>(writer: &mut W, width: u32, height: u32, rgba: &[u8]) -> io::Result<()> {
+    let row_size = width as usize * BYTES_PER_PIXEL;
+    if rgba.len() != row_size * height as usize {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            "pixel data doesn't match the image size",
+        ));
+    }
+
+    let mut header = Vec::with_capacity(13);
+    header.extend_from_slice(&width.to_be_bytes());
+    header.extend_from_slice(&height.to_be_bytes());
+    // Compression, filter and interlace methods are all 0
+    header.extend_from_slice(&[BIT_DEPTH, COLOR_TYPE_RGBA, 0, 0, 0]);
+
+    let mut scanlines = Vec::with_capacity((row_size + 1) * height as usize);
+    for row in rgba.chunks_exact(row_size.max(1)) {
+        scanlines.push(FILTER_NONE);
+        scanlines.extend_from_slice(row);
+    }
+
+    writer.write_all(&SIGNATURE)?;
+    write_chunk(writer, b"IHDR", &header)?;
+    write_chunk(writer, b"IDAT", &zlib_stored(&scanlines))?;
+    write_chunk(writer, b"IEND", &[])
+}
+
+pub fn save_png<P: AsRef<Path>>(path: P, width: u32, height: u32, rgba: &[u8]) -> io::Result<()> {
+    let mut writer = BufWriter::new(File::create(path)?);
+    write_png(&mut writer, width, height, rgba)?;
+    writer.flush()
+}
+
+fn write_chunk<W: Write>(writer: &mut W, kind: &[u8; 4], data: &[u8]) -> io::Result<()> {
+    writer.write_all(&(data.len() as u32).to_be_bytes())?;
+    writer.write_all(kind)?;
+    writer.write_all(data)?;
+    // The CRC covers the chunk type as well as the data
+    let crc = crc32(kind.iter().chain(data));
+    writer.write_all(&crc.to_be_bytes())
+}
+
+fn zlib_stored(data: &[u8]) -> Vec<u8> {
+    let mut output = Vec::with_capacity(data.len() + data.len() / MAX_STORED_BLOCK * 5 + 16);
+    output.extend_from_slice(&ZLIB_HEADER);
+
+    let mut blocks = data.chunks(MAX_STORED_BLOCK).peekable();
+    if blocks.peek().is_none() {
+        // Even empty data needs one final block
+        output.extend_from_slice(&[0x01, 0x00, 0x00, 0xFF, 0xFF]);
+    }
+    while let Some(block) = blocks.next() {
+        let is_final = blocks.peek().is_none();
+        let length = block.len() as u16;
+        output.push(is_final as u8);
+        output.extend_from_slice(&length.to_le_bytes());
+        output.extend_from_slice(&(!length).to_le_bytes());
+        output.extend_from_slice(block);
+    }
+
+    output.extend_from_slice(&adler32(data).to_be_bytes());
+    output
+}
+
+fn crc32<'a, I: IntoIterator<Item = &'a u8>>(bytes: I) -> u32 {
+    let mut crc = 0xFFFF_FFFFu32;
+    for &byte in bytes {
+        crc ^= byte as u32;
+        for _ in 0..8 {
+            crc = if crc & 1 != 0 {
+                (crc >> 1) ^ 0xEDB8_8320
+            } else {
+                crc >> 1
+            };
+        }
+    }
+    !crc
+}
+
+fn adler32(data: &[u8]) -> u32 {
+    let (mut a, mut b) = (1u32, 0u32);
+    for &byte in data {
+        a = (a + byte as u32) % ADLER_MODULUS;
+        b = (b + a) % ADLER_MODULUS;
+    }
+    b << 16 | a
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn checksums_match_reference_values() {
+        assert_eq!(crc32(b"123456789"), 0xCBF4_3926);
+        assert_eq!(adler32(b"Wikipedia"), 0x11E6_0398);
+    }
+
+    #[test]
+    fn writes_a_one_pixel_image() {
+        let mut output = Vec::new();
+        write_png(&mut output, 1, 1, &[0x11, 0x22, 0x33, 0x44]).unwrap();
+
+        assert_eq!(output[..8], SIGNATURE);
+        // IHDR: length, type, 1x1, 8-bit RGBA, then the CRC every 1x1 RGBA PNG has
+        assert_eq!(output[8..12], 13u32.to_be_bytes());
+        assert_eq!(&output[12..16], b"IHDR");
+        assert_eq!(output[16..29], [0, 0, 0, 1, 0, 0, 0, 1, 8, 6, 0, 0, 0]);
+        assert_eq!(output[29..33], 0x1F15_C489u32.to_be_bytes());
+
+        // IDAT holds the zlib header, one final stored block and the Adler-32 of the scanline
+        let scanline = [FILTER_NONE, 0x11, 0x22, 0x33, 0x44];
+        let idat_length = u32::from_be_bytes(output[33..37].try_into().unwrap()) as usize;
+        assert_eq!(&output[37..41], b"IDAT");
+        let idat = &output[41..41 + idat_length];
+        assert_eq!(idat[..2], ZLIB_HEADER);
+        assert_eq!(idat[2..7], [0x01, 0x05, 0x00, 0xFA, 0xFF]);
+        assert_eq!(idat[7..12], scanline);
+        assert_eq!(idat[12..], 0x0159_00ABu32.to_be_bytes());
+
+        let iend = &output[41 + idat_length + 4..];
+        assert_eq!(
+            iend,
+            [0, 0, 0, 0, b'I', b'E', b'N', b'D', 0xAE, 0x42, 0x60, 0x82]
+        );
+    }
+
+    #[test]
+    fn rejects_mismatched_pixel_data() {
+        assert!(write_png(&mut Vec::new(), 2, 2, &[0; 4]).is_err());
+    }
+}
@@ -0,0 +1,269 @@
+use std::collections::VecDeque;
+
+use super::{
+    apply_palette, row_pixel, Ppu, Sprite, LCDC_BG_ENABLE, LCDC_OBJ_ENABLE, LCDC_WINDOW_ENABLE,
+    OBJ_BEHIND_BG, OBJ_PALETTE, SCREEN_WIDTH, TILE_MAP_WIDTH, WINDOW_X_MAX, WINDOW_X_OFFSET,
+};
+
+// The first tile fetched on every line is thrown away
+const LINE_START_DOTS: u32 = 6;
+// Fetching a sprite pauses the background fetcher for at least this long
+const SPRITE_FETCH_DOTS: u32 = 6;
+// The extra penalty for a sprite depends on where it falls in the background tile
+const MAX_ALIGNMENT_PENALTY: u32 = 5;
+const TILE_WIDTH: u8 = 8;
+
+#[derive(Clone, Copy, PartialEq, Eq)]
+enum FetcherStep {
+    TileNumber,
+    DataLow,
+    DataHigh,
+    Push,
+}
+
+#[derive(Clone, Copy)]
+struct SpritePixel {
+    color: u8,
+    // Kept as attributes so the palette is looked up when the pixel is mixed
+    attributes: u8,
+}
+
+pub struct PixelFifo {
+    // Color indices waiting to be mixed, the palette is applied on the way out
+    background: VecDeque<u8>,
+    sprites: VecDeque<SpritePixel>,
+    step: FetcherStep,
+    // Every fetcher step except the push takes two dots
+    step_dots: u8,
+    // Tile column the fetcher is working on, relative to the start of the line or window
+    fetcher_x: u8,
+    tile_number: u8,
+    tile_low: u8,
+    tile_high: u8,
+    // Pixels pushed to the LCD so far on this line
+    lcd_x: u8,
+    // Background pixels dropped at the start of the line for the SCX fine scroll
+    discard: u8,
+    // Sprites on this line that haven't been fetched yet, in X order
+    pending_sprites: VecDeque<Sprite>,
+    // Dots left during which the fetcher and the LCD are both paused
+    stall: u32,
+    in_window: bool,
+    // Set once LY has matched WY during the current frame
+    window_y_reached: bool,
+    // Background tile that already paid the alignment penalty for a sprite
+    penalized_tile: Option<u8>,
+}
+
+impl PixelFifo {
+    pub fn new() -> PixelFifo {
+        PixelFifo {
+            background: VecDeque::with_capacity(16),
+            sprites: VecDeque::with_capacity(8),
+            step: FetcherStep::TileNumber,
+            step_dots: 0,
+            fetcher_x: 0,
+            tile_number: 0,
+            tile_low: 0,
+            tile_high: 0,
+            lcd_x: 0,
+            discard: 0,
+            pending_sprites: VecDeque::new(),
+            stall: 0,
+            in_window: false,
+            window_y_reached: false,
+            penalized_tile: None,
+        }
+    }
+
+    // Resets the pipeline at the start of mode 3
+    pub fn start_line(&mut self, ly: u8, scx: u8, wy: u8, sprites: Vec<Sprite>) {
+        if ly == 0 {
+            self.window_y_reached = false;
+        }
+        if ly == wy {
+            self.window_y_reached = true;
+        }
+        self.background.clear();
+        self.sprites.clear();
+        self.step = FetcherStep::TileNumber;
+        self.step_dots = 0;
+        self.fetcher_x = 0;
+        self.lcd_x = 0;
+        self.discard = scx % TILE_WIDTH;
+        self.pending_sprites = sprites.into();
+        self.stall = LINE_START_DOTS;
+        self.in_window = false;
+        self.penalized_tile = None;
+    }
+
+    // Runs one dot of mode 3 and returns true once the last pixel of the line is out
+    pub fn step(&mut self, ppu: &mut Ppu) -> bool {
+        if self.stall > 0 {
+            self.stall -= 1;
+            return false;
+        }
+
+        // Hitting the window throws away whatever background was queued and restarts the fetcher
+        if !self.in_window && self.window_triggered(ppu) {
+            self.in_window = true;
+            self.background.clear();
+            self.step = FetcherStep::TileNumber;
+            self.step_dots = 0;
+            self.fetcher_x = 0;
+        }
+
+        if ppu.lcdc & LCDC_OBJ_ENABLE != 0 && self.discard == 0 {
+            let next = self.pending_sprites.front().copied();
+            if let Some(sprite) = next.filter(|sprite| sprite.x <= self.lcd_x + TILE_WIDTH) {
+                self.pending_sprites.pop_front();
+                self.fetch_sprite(ppu, &sprite);
+                // This dot is part of the fetch as well
+                self.stall = SPRITE_FETCH_DOTS + self.alignment_penalty(ppu, &sprite) - 1;
+                return false;
+            }
+        }
+
+        self.step_fetcher(ppu);
+        self.push_pixel(ppu)
+    }
+
+    fn window_triggered(&self, ppu: &Ppu) -> bool {
+        ppu.lcdc & LCDC_WINDOW_ENABLE != 0
+            && self.window_y_reached
+            && ppu.wx <= WINDOW_X_MAX
+            && self.lcd_x + WINDOW_X_OFFSET >= ppu.wx
+    }
+
+    fn step_fetcher(&mut self, ppu: &Ppu) {
+        // The fetcher can only push once the FIFO has drained
+        if self.step == FetcherStep::Push {
+            if self.background.is_empty() {
+                let (low, high) = (self.tile_low, self.tile_high);
+                self.background
+                    .extend((0..TILE_WIDTH).map(|column| row_pixel(low, high, column)));
+                self.fetcher_x = self.fetcher_x.wrapping_add(1);
+                self.step = FetcherStep::TileNumber;
+            }
+            return;
+        }
+
+        // Registers and VRAM are read on the second dot of each step, so mid-line
+        // writes to SCX, SCY or LCDC affect the tiles fetched after them
+        self.step_dots += 1;
+        if self.step_dots < 2 {
+            return;
+        }
+        self.step_dots = 0;
+        self.step = match self.step {
+            FetcherStep::TileNumber => {
+                self.tile_number = self.fetch_tile_number(ppu);
+                FetcherStep::DataLow
+            }
+            FetcherStep::DataLow => {
+                self.tile_low = ppu.read_vram(self.tile_row_address(ppu));
+                FetcherStep::DataHigh
+            }
+            _ => {
+                self.tile_high = ppu.read_vram(self.tile_row_address(ppu) + 1);
+                FetcherStep::Push
+            }
+        };
+    }
+
+    fn fetch_tile_number(&self, ppu: &Ppu) -> u8 {
+        let (tile_map, column, y) = if self.in_window {
+            (ppu.window_tile_map(), self.fetcher_x, ppu.window_line)
+        } else {
+            (
+                ppu.background_tile_map(),
+                (ppu.scx / TILE_WIDTH).wrapping_add(self.fetcher_x),
+                ppu.ly.wrapping_add(ppu.scy),
+            )
+        };
+        let column = column as u16 % TILE_MAP_WIDTH;
+        ppu.read_vram(tile_map + (y as u16 / 8) * TILE_MAP_WIDTH + column)
+    }
+
+    fn tile_row_address(&self, ppu: &Ppu) -> u16 {
+        let y = if self.in_window {
+            ppu.window_line
+        } else {
+            ppu.ly.wrapping_add(ppu.scy)
+        };
+        ppu.tile_data_address(self.tile_number) + (y % 8) as u16 * 2
+    }
+
+    // Sprite pixels only fill slots that are still transparent, which keeps the
+    // lower X and earlier OAM entry priority of the sprites fetched before
+    fn fetch_sprite(&mut self, ppu: &Ppu, sprite: &Sprite) {
+        // Sprites hanging off the left edge have their first columns cut off
+        let hidden_columns = self.lcd_x + TILE_WIDTH - sprite.x;
+        for column in hidden_columns..TILE_WIDTH {
+            let pixel = SpritePixel {
+                color: ppu.sprite_color(sprite, column),
+                attributes: sprite.attributes,
+            };
+            match self.sprites.get_mut((column - hidden_columns) as usize) {
+                Some(existing) if existing.color == 0 => *existing = pixel,
+                Some(_) => {}
+                None => self.sprites.push_back(pixel),
+            }
+        }
+    }
+
+    // The first sprite in a background tile waits for that tile's fetch to finish,
+    // which costs more the further left in the tile the sprite starts
+    fn alignment_penalty(&mut self, ppu: &Ppu, sprite: &Sprite) -> u32 {
+        let x = if self.in_window {
+            sprite.x.wrapping_sub(ppu.wx.wrapping_add(1))
+        } else {
+            sprite.x.wrapping_add(ppu.scx)
+        };
+        let tile = x / TILE_WIDTH;
+        if self.penalized_tile == Some(tile) {
+            return 0;
+        }
+        self.penalized_tile = Some(tile);
+        MAX_ALIGNMENT_PENALTY.saturating_sub((x % TILE_WIDTH) as u32)
+    }
+
+    fn push_pixel(&mut self, ppu: &mut Ppu) -> bool {
+        let Some(color) = self.background.pop_front() else {
+            return false;
+        };
+        if self.discard > 0 {
+            self.discard -= 1;
+            return false;
+        }
+
+        // LCDC, BGP and the OBP registers are sampled per pixel
+        let background = if ppu.lcdc & LCDC_BG_ENABLE != 0 {
+            color
+        } else {
+            0
+        };
+        let mut shade = apply_palette(ppu.bgp, background);
+        if let Some(sprite) = self.sprites.pop_front() {
+            let hidden = sprite.attributes & OBJ_BEHIND_BG != 0 && background != 0;
+            if sprite.color != 0 && ppu.lcdc & LCDC_OBJ_ENABLE != 0 && !hidden {
+                let palette = if sprite.attributes & OBJ_PALETTE != 0 {
+                    ppu.obp1
+                } else {
+                    ppu.obp0
+                };
+                shade = apply_palette(palette, sprite.color);
+            }
+        }
+
+        ppu.back_buffer[ppu.ly as usize * SCREEN_WIDTH + self.lcd_x as usize] = shade;
+        self.lcd_x += 1;
+        if (self.lcd_x as usize) < SCREEN_WIDTH {
+            return false;
+        }
+        if self.in_window {
+            ppu.window_line += 1;
+        }
+        true
+    }
+}
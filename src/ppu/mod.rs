@@ -0,0 +1,599 @@
+mod fifo;
+mod scanline;
+
+use crate::interrupts::Interrupt;
+
+use self::fifo::PixelFifo;
+
+pub const SCREEN_WIDTH: usize = 160;
+pub const SCREEN_HEIGHT: usize = 144;
+
+pub const LCDC_REGISTER: u16 = 0xFF40;
+pub const STAT_REGISTER: u16 = 0xFF41;
+pub const SCY_REGISTER: u16 = 0xFF42;
+pub const SCX_REGISTER: u16 = 0xFF43;
+pub const LY_REGISTER: u16 = 0xFF44;
+pub const LYC_REGISTER: u16 = 0xFF45;
+pub const BGP_REGISTER: u16 = 0xFF47;
+pub const OBP0_REGISTER: u16 = 0xFF48;
+pub const OBP1_REGISTER: u16 = 0xFF49;
+pub const WY_REGISTER: u16 = 0xFF4A;
+pub const WX_REGISTER: u16 = 0xFF4B;
+
+const VRAM_START: u16 = 0x8000;
+const OAM_START: u16 = 0xFE00;
+const VRAM_SIZE: usize = 0x2000;
+const OAM_SIZE: usize = 0xA0;
+
+const TILE_MAP_LOW: u16 = 0x9800;
+const TILE_MAP_HIGH: u16 = 0x9C00;
+// Unsigned tile indices count up from 0x8000, signed ones are centered on 0x9000
+const TILE_DATA_UNSIGNED: u16 = 0x8000;
+const TILE_DATA_SIGNED: u16 = 0x9000;
+const TILE_SIZE: u16 = 16;
+const TILE_MAP_WIDTH: u16 = 32;
+
+const DOTS_PER_LINE: u32 = 456;
+const OAM_SEARCH_DOTS: u32 = 80;
+const PIXEL_TRANSFER_DOTS: u32 = 172;
+const LINES_PER_FRAME: u8 = 154;
+// A full frame including VBlank, about 59.7 of them per second
+pub const DOTS_PER_FRAME: u32 = DOTS_PER_LINE * LINES_PER_FRAME as u32;
+
+const LCDC_LCD_ENABLE: u8 = 0x80;
+const LCDC_WINDOW_TILE_MAP: u8 = 0x40;
+const LCDC_WINDOW_ENABLE: u8 = 0x20;
+const LCDC_TILE_DATA: u8 = 0x10;
+const LCDC_BG_TILE_MAP: u8 = 0x08;
+const LCDC_OBJ_SIZE: u8 = 0x04;
+const LCDC_OBJ_ENABLE: u8 = 0x02;
+const LCDC_BG_ENABLE: u8 = 0x01;
+
+const STAT_HBLANK_INTERRUPT: u8 = 0x08;
+const STAT_VBLANK_INTERRUPT: u8 = 0x10;
+const STAT_OAM_INTERRUPT: u8 = 0x20;
+const STAT_LYC_INTERRUPT: u8 = 0x40;
+const STAT_WRITABLE_MASK: u8 = 0x78;
+const STAT_COINCIDENCE: u8 = 0x04;
+
+const OAM_ENTRY_COUNT: usize = 40;
+const MAX_SPRITES_PER_LINE: usize = 10;
+const OBJ_BEHIND_BG: u8 = 0x80;
+const OBJ_Y_FLIP: u8 = 0x40;
+const OBJ_X_FLIP: u8 = 0x20;
+const OBJ_PALETTE: u8 = 0x10;
+
+// The window is offset by 7 so WX=7 lines it up with the left edge
+const WINDOW_X_OFFSET: u8 = 7;
+const WINDOW_X_MAX: u8 = 166;
+
+// Shades 0-3 go from lightest to darkest
+pub const SHADE_RGBA: [[u8; 4]; 4] = [
+    [0xFF, 0xFF, 0xFF, 0xFF],
+    [0xAA, 0xAA, 0xAA, 0xFF],
+    [0x55, 0x55, 0x55, 0xFF],
+    [0x00, 0x00, 0x00, 0xFF],
+];
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Mode {
+    HBlank = 0,
+    VBlank = 1,
+    OamSearch = 2,
+    PixelTransfer = 3,
+}
+
+// The scanline backend draws each line in one go at the end of mode 3, while the pixel FIFO
+// backend pushes one pixel per dot so mid-line register writes show up where they happened
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum PpuBackend {
+    Scanline,
+    PixelFifo,
+}
+
+#[derive(Clone, Copy)]
+struct Sprite {
+    y: u8,
+    x: u8,
+    tile: u8,
+    attributes: u8,
+}
+
+pub struct Ppu {
+    vram: [u8; VRAM_SIZE],
+    oam: [u8; OAM_SIZE],
+    lcdc: u8,
+    // Only the interrupt enables are stored, the mode and coincidence bits are derived
+    stat: u8,
+    scy: u8,
+    scx: u8,
+    ly: u8,
+    lyc: u8,
+    bgp: u8,
+    obp0: u8,
+    obp1: u8,
+    wy: u8,
+    wx: u8,
+    mode: Mode,
+    // Latched result of the last LY == LYC comparison
+    coincidence: bool,
+    // The four STAT sources are ORed into a single line and only its rising edge
+    // requests an interrupt, so a source going high while another is already high is lost
+    stat_line: bool,
+    // Position within the current line, 0-455
+    dot: u32,
+    // The window keeps its own line counter that only advances on lines where it was drawn
+    window_line: u8,
+    // Interrupt bits raised since the last tick returned
+    requested_interrupts: u8,
+    // The line being drawn goes into the back buffer, which is copied over at VBlank
+    back_buffer: [u8; SCREEN_WIDTH * SCREEN_HEIGHT],
+    frame: [u8; SCREEN_WIDTH * SCREEN_HEIGHT],
+    frame_ready: bool,
+    // Only present with the pixel FIFO backend
+    fifo: Option<PixelFifo>,
+}
+
+impl Ppu {
+    pub fn new(backend: PpuBackend) -> Ppu {
+        let fifo = match backend {
+            PpuBackend::Scanline => None,
+            PpuBackend::PixelFifo => Some(PixelFifo::new()),
+        };
+        Ppu {
+            vram: [0; VRAM_SIZE],
+            oam: [0; OAM_SIZE],
+            // Values the boot ROM leaves behind
+            lcdc: 0x91,
+            stat: 0,
+            scy: 0,
+            scx: 0,
+            ly: 0,
+            lyc: 0,
+            bgp: 0xFC,
+            obp0: 0xFF,
+            obp1: 0xFF,
+            wy: 0,
+            wx: 0,
+            mode: Mode::OamSearch,
+            coincidence: true,
+            stat_line: false,
+            dot: 0,
+            window_line: 0,
+            requested_interrupts: 0,
+            back_buffer: [0; SCREEN_WIDTH * SCREEN_HEIGHT],
+            frame: [0; SCREEN_WIDTH * SCREEN_HEIGHT],
+            frame_ready: false,
+            fifo,
+        }
+    }
+
+    // Advances by the given number of dots and returns the interrupt bits to request
+    pub fn tick(&mut self, dots: u32) -> u8 {
+        if self.lcdc & LCDC_LCD_ENABLE != 0 {
+            for _ in 0..dots {
+                self.step_dot();
+            }
+        }
+        std::mem::take(&mut self.requested_interrupts)
+    }
+
+    // Shade indices 0-3 of the last completed frame, row by row
+    pub fn framebuffer(&self) -> &[u8] {
+        &self.frame
+    }
+
+    pub fn framebuffer_rgba(&self) -> Vec<u8> {
+        self.frame
+            .iter()
+            .flat_map(|&shade| SHADE_RGBA[shade as usize])
+            .collect()
+    }
+
+    // True once per frame, when VBlank starts
+    pub fn take_frame_ready(&mut self) -> bool {
+        std::mem::take(&mut self.frame_ready)
+    }
+
+    pub fn mode(&self) -> Mode {
+        self.mode
+    }
+
+    pub fn read_vram(&self, address: u16) -> u8 {
+        self.vram[(address - VRAM_START) as usize]
+    }
+
+    pub fn write_vram(&mut self, address: u16, value: u8) {
+        self.vram[(address - VRAM_START) as usize] = value;
+    }
+
+    pub fn read_oam(&self, address: u16) -> u8 {
+        self.oam[(address - OAM_START) as usize]
+    }
+
+    pub fn write_oam(&mut self, address: u16, value: u8) {
+        self.oam[(address - OAM_START) as usize] = value;
+    }
+
+    pub fn read_register(&self, address: u16) -> u8 {
+        match address {
+            LCDC_REGISTER => self.lcdc,
+            // Bit 7 is unused and always reads back as 1
+            STAT_REGISTER => {
+                let coincidence = if self.coincidence {
+                    STAT_COINCIDENCE
+                } else {
+                    0
+                };
+                0x80 | self.stat | coincidence | self.mode as u8
+            }
+            SCY_REGISTER => self.scy,
+            SCX_REGISTER => self.scx,
+            LY_REGISTER => self.ly,
+            LYC_REGISTER => self.lyc,
+            BGP_REGISTER => self.bgp,
+            OBP0_REGISTER => self.obp0,
+            OBP1_REGISTER => self.obp1,
+            WY_REGISTER => self.wy,
+            WX_REGISTER => self.wx,
+            _ => 0xFF,
+        }
+    }
+
+    pub fn write_register(&mut self, address: u16, value: u8) {
+        match address {
+            LCDC_REGISTER => self.write_lcdc(value),
+            STAT_REGISTER => {
+                self.stat = value & STAT_WRITABLE_MASK;
+                self.update_stat_line();
+            }
+            SCY_REGISTER => self.scy = value,
+            SCX_REGISTER => self.scx = value,
+            // LY is read only
+            LY_REGISTER => {}
+            LYC_REGISTER => {
+                self.lyc = value;
+                if self.lcdc & LCDC_LCD_ENABLE != 0 {
+                    self.compare_lyc();
+                }
+            }
+            BGP_REGISTER => self.bgp = value,
+            OBP0_REGISTER => self.obp0 = value,
+            OBP1_REGISTER => self.obp1 = value,
+            WY_REGISTER => self.wy = value,
+            WX_REGISTER => self.wx = value,
+            _ => {}
+        }
+    }
+
+    fn step_dot(&mut self) {
+        self.dot += 1;
+        if (self.ly as usize) < SCREEN_HEIGHT {
+            if self.dot == OAM_SEARCH_DOTS {
+                self.set_mode(Mode::PixelTransfer);
+                // The scanline renderer searches OAM itself when it draws the line
+                if self.fifo.is_some() {
+                    let sprites = self.oam_search();
+                    if let Some(fifo) = &mut self.fifo {
+                        fifo.start_line(self.ly, self.scx, self.wy, sprites);
+                    }
+                }
+            } else if self.mode == Mode::PixelTransfer && self.transfer_pixels() {
+                self.set_mode(Mode::HBlank);
+            }
+        }
+
+        if self.dot < DOTS_PER_LINE {
+            return;
+        }
+        self.dot = 0;
+        self.ly = (self.ly + 1) % LINES_PER_FRAME;
+        if self.ly == 0 {
+            self.window_line = 0;
+        }
+        self.compare_lyc();
+
+        if (self.ly as usize) < SCREEN_HEIGHT {
+            self.set_mode(Mode::OamSearch);
+        } else if self.ly as usize == SCREEN_HEIGHT {
+            self.set_mode(Mode::VBlank);
+            self.requested_interrupts |= Interrupt::VBlank.bit();
+            self.frame.copy_from_slice(&self.back_buffer);
+            self.frame_ready = true;
+        }
+    }
+
+    // Switching the LCD off parks it at the start of line 0 in mode 0 until it comes back on,
+    // and the screen stays blank meanwhile. The STAT line stays low while it's off and is
+    // only evaluated again once it comes back on.
+    fn write_lcdc(&mut self, value: u8) {
+        let was_enabled = self.lcdc & LCDC_LCD_ENABLE != 0;
+        self.lcdc = value;
+        let enabled = self.lcdc & LCDC_LCD_ENABLE != 0;
+        if was_enabled && !enabled {
+            self.ly = 0;
+            self.dot = 0;
+            self.window_line = 0;
+            self.set_mode(Mode::HBlank);
+            self.frame.fill(0);
+        } else if !was_enabled && enabled {
+            self.set_mode(Mode::OamSearch);
+            self.compare_lyc();
+        }
+    }
+
+    fn compare_lyc(&mut self) {
+        self.coincidence = self.ly == self.lyc;
+        self.update_stat_line();
+    }
+
+    fn update_stat_line(&mut self) {
+        if self.lcdc & LCDC_LCD_ENABLE == 0 {
+            self.stat_line = false;
+            return;
+        }
+        let mode_source = match self.mode {
+            Mode::HBlank => STAT_HBLANK_INTERRUPT,
+            Mode::VBlank => STAT_VBLANK_INTERRUPT,
+            Mode::OamSearch => STAT_OAM_INTERRUPT,
+            Mode::PixelTransfer => 0,
+        };
+        let line = self.stat & mode_source != 0
+            || (self.coincidence && self.stat & STAT_LYC_INTERRUPT != 0);
+        if line && !self.stat_line {
+            self.requested_interrupts |= Interrupt::LcdStat.bit();
+        }
+        self.stat_line = line;
+    }
+
+    // Runs one dot of mode 3 and returns true once the line is complete
+    fn transfer_pixels(&mut self) -> bool {
+        match self.fifo.take() {
+            Some(mut fifo) => {
+                let done = fifo.step(self);
+                self.fifo = Some(fifo);
+                done
+            }
+            None if self.dot == OAM_SEARCH_DOTS + PIXEL_TRANSFER_DOTS => {
+                scanline::render_line(self);
+                true
+            }
+            None => false,
+        }
+    }
+
+    fn set_mode(&mut self, mode: Mode) {
+        self.mode = mode;
+        self.update_stat_line();
+    }
+
+    // Up to 10 sprites overlapping the current line, in drawing priority order.
+    // On DMG the sprite with the lower X wins and ties go to the earlier OAM entry.
+    fn oam_search(&self) -> Vec<Sprite> {
+        let height = self.sprite_height();
+        let line = self.ly as u16 + 16;
+        let mut sprites: Vec<Sprite> = self
+            .oam
+            .chunks_exact(4)
+            .take(OAM_ENTRY_COUNT)
+            .map(|entry| Sprite {
+                y: entry[0],
+                x: entry[1],
+                tile: entry[2],
+                attributes: entry[3],
+            })
+            .filter(|sprite| line >= sprite.y as u16 && line < sprite.y as u16 + height)
+            .take(MAX_SPRITES_PER_LINE)
+            .collect();
+        sprites.sort_by_key(|sprite| sprite.x);
+        sprites
+    }
+
+    fn sprite_height(&self) -> u16 {
+        if self.lcdc & LCDC_OBJ_SIZE != 0 {
+            16
+        } else {
+            8
+        }
+    }
+
+    // Color index 0-3 of a background or window pixel, before the palette is applied
+    fn background_color(&self, tile_map: u16, x: u8, y: u8) -> u8 {
+        let map_address = tile_map + (y as u16 / 8) * TILE_MAP_WIDTH + x as u16 / 8;
+        let tile_address = self.tile_data_address(self.read_vram(map_address));
+        self.tile_pixel(tile_address, y % 8, x % 8)
+    }
+
+    // Where the background and window find a tile, depending on the LCDC addressing mode
+    fn tile_data_address(&self, tile_index: u8) -> u16 {
+        if self.lcdc & LCDC_TILE_DATA != 0 {
+            TILE_DATA_UNSIGNED + tile_index as u16 * TILE_SIZE
+        } else {
+            TILE_DATA_SIGNED.wrapping_add_signed(tile_index as i8 as i16 * TILE_SIZE as i16)
+        }
+    }
+
+    // Color index 0-3 of the given column of a sprite on the current line
+    fn sprite_color(&self, sprite: &Sprite, column: u8) -> u8 {
+        let height = self.sprite_height() as u8;
+        let mut row = (self.ly + 16).wrapping_sub(sprite.y);
+        if sprite.attributes & OBJ_Y_FLIP != 0 {
+            row = height - 1 - row;
+        }
+        let column = if sprite.attributes & OBJ_X_FLIP != 0 {
+            7 - column
+        } else {
+            column
+        };
+        // Tall sprites ignore the lowest bit of the tile index
+        let tile = if height == 16 {
+            sprite.tile & 0xFE
+        } else {
+            sprite.tile
+        };
+        self.tile_pixel(TILE_DATA_UNSIGNED + tile as u16 * TILE_SIZE, row, column)
+    }
+
+    fn sprite_palette(&self, sprite: &Sprite) -> u8 {
+        if sprite.attributes & OBJ_PALETTE != 0 {
+            self.obp1
+        } else {
+            self.obp0
+        }
+    }
+
+    // Each row of a tile is two bytes, the first holding the low bit of every pixel
+    fn tile_pixel(&self, tile_address: u16, row: u8, column: u8) -> u8 {
+        let row_address = tile_address + row as u16 * 2;
+        let low = self.read_vram(row_address);
+        let high = self.read_vram(row_address + 1);
+        row_pixel(low, high, column)
+    }
+
+    fn background_tile_map(&self) -> u16 {
+        if self.lcdc & LCDC_BG_TILE_MAP != 0 {
+            TILE_MAP_HIGH
+        } else {
+            TILE_MAP_LOW
+        }
+    }
+
+    fn window_tile_map(&self) -> u16 {
+        if self.lcdc & LCDC_WINDOW_TILE_MAP != 0 {
+            TILE_MAP_HIGH
+        } else {
+            TILE_MAP_LOW
+        }
+    }
+
+    fn window_visible(&self) -> bool {
+        self.lcdc & LCDC_WINDOW_ENABLE != 0 && self.ly >= self.wy && self.wx <= WINDOW_X_MAX
+    }
+}
+
+fn row_pixel(low: u8, high: u8, column: u8) -> u8 {
+    let bit = 7 - column;
+    ((high >> bit) & 0b1) << 1 | ((low >> bit) & 0b1)
+}
+
+fn apply_palette(palette: u8, color: u8) -> u8 {
+    (palette >> (color * 2)) & 0b11
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn stat_interrupt_fires_on_the_rising_edge_only() {
+        let mut ppu = Ppu::new(PpuBackend::Scanline);
+        // LY == LYC == 0 already, so enabling the source raises the line
+        ppu.write_register(STAT_REGISTER, STAT_LYC_INTERRUPT | STAT_HBLANK_INTERRUPT);
+        assert_eq!(ppu.tick(0), Interrupt::LcdStat.bit());
+
+        // HBlank starts while the coincidence keeps the line high
+        ppu.tick(OAM_SEARCH_DOTS + PIXEL_TRANSFER_DOTS);
+        assert_eq!(ppu.mode(), Mode::HBlank);
+        assert_eq!(ppu.tick(0), 0);
+
+        // Rewriting STAT doesn't retrigger a line that's already high
+        ppu.write_register(STAT_REGISTER, STAT_LYC_INTERRUPT | STAT_HBLANK_INTERRUPT);
+        assert_eq!(ppu.tick(0), 0);
+    }
+
+    #[test]
+    fn lyc_coincidence_requests_an_interrupt() {
+        let mut ppu = Ppu::new(PpuBackend::Scanline);
+        ppu.write_register(LYC_REGISTER, 2);
+        ppu.write_register(STAT_REGISTER, STAT_LYC_INTERRUPT);
+        assert_eq!(ppu.tick(DOTS_PER_LINE), 0);
+        assert_eq!(ppu.read_register(STAT_REGISTER) & STAT_COINCIDENCE, 0);
+
+        assert_eq!(ppu.tick(DOTS_PER_LINE), Interrupt::LcdStat.bit());
+        assert_eq!(ppu.read_register(LY_REGISTER), 2);
+        assert_ne!(ppu.read_register(STAT_REGISTER) & STAT_COINCIDENCE, 0);
+    }
+
+    #[test]
+    fn no_stat_interrupt_while_the_lcd_is_off() {
+        let mut ppu = Ppu::new(PpuBackend::Scanline);
+        ppu.write_register(STAT_REGISTER, STAT_HBLANK_INTERRUPT);
+        assert_eq!(ppu.tick(0), 0);
+
+        // Off parks the LCD in mode 0, which mustn't count as entering HBlank
+        ppu.write_register(LCDC_REGISTER, 0x11);
+        assert_eq!(ppu.mode(), Mode::HBlank);
+        assert_eq!(ppu.tick(DOTS_PER_LINE), 0);
+        ppu.write_register(STAT_REGISTER, STAT_HBLANK_INTERRUPT | STAT_LYC_INTERRUPT);
+        assert_eq!(ppu.tick(0), 0);
+
+        // Back on, LY == LYC raises the line again
+        ppu.write_register(LCDC_REGISTER, 0x91);
+        assert_eq!(ppu.tick(0), Interrupt::LcdStat.bit());
+    }
+
+    // Tiles, a scrolled background, a window and sprites with every attribute in use
+    fn draw_scene(ppu: &mut Ppu) {
+        let tiles: [[u8; 2]; 4] = [[0x00, 0x00], [0xF0, 0x0F], [0xAA, 0xCC], [0xFF, 0xFF]];
+        for (index, [low, high]) in tiles.into_iter().enumerate() {
+            for row in 0..8u16 {
+                let address = TILE_DATA_UNSIGNED + index as u16 * TILE_SIZE + row * 2;
+                ppu.write_vram(address, low.rotate_left(row as u32));
+                ppu.write_vram(address + 1, high);
+            }
+        }
+        for offset in 0..0x400u16 {
+            let (x, y) = (offset % TILE_MAP_WIDTH, offset / TILE_MAP_WIDTH);
+            ppu.write_vram(TILE_MAP_LOW + offset, ((x + y) % 4) as u8);
+            ppu.write_vram(TILE_MAP_HIGH + offset, (x % 2) as u8 + 2);
+        }
+        let sprites = [
+            [16 + 10, 8 + 20, 2, 0],
+            [16 + 14, 8 + 24, 1, OBJ_PALETTE],
+            [16 + 70, 8 + 80, 1, OBJ_X_FLIP | OBJ_Y_FLIP | OBJ_PALETTE],
+            [16 + 100, 8 + 156, 2, OBJ_BEHIND_BG],
+            [16 + 130, 4, 3, 0],
+        ];
+        for (index, sprite) in sprites.iter().enumerate() {
+            for (byte, &value) in sprite.iter().enumerate() {
+                ppu.write_oam(OAM_START + (index * 4 + byte) as u16, value);
+            }
+        }
+        ppu.write_register(SCX_REGISTER, 3);
+        ppu.write_register(SCY_REGISTER, 5);
+        ppu.write_register(WY_REGISTER, 60);
+        ppu.write_register(WX_REGISTER, 87);
+        ppu.write_register(BGP_REGISTER, 0xE4);
+        ppu.write_register(OBP0_REGISTER, 0xD2);
+        ppu.write_register(OBP1_REGISTER, 0x1B);
+        ppu.write_register(
+            LCDC_REGISTER,
+            LCDC_LCD_ENABLE
+                | LCDC_WINDOW_TILE_MAP
+                | LCDC_WINDOW_ENABLE
+                | LCDC_TILE_DATA
+                | LCDC_OBJ_ENABLE
+                | LCDC_BG_ENABLE,
+        );
+    }
+
+    #[test]
+    fn both_backends_draw_the_same_frame() {
+        let [scanline, fifo] = [PpuBackend::Scanline, PpuBackend::PixelFifo].map(|backend| {
+            let mut ppu = Ppu::new(backend);
+            draw_scene(&mut ppu);
+            ppu.tick(DOTS_PER_FRAME);
+            assert!(ppu.take_frame_ready());
+            ppu.framebuffer().to_vec()
+        });
+        // Every shade shows up somewhere, so the scene isn't trivially blank
+        assert!((0..4).all(|shade| scanline.contains(&shade)));
+        for (line, (scanline, fifo)) in scanline
+            .chunks(SCREEN_WIDTH)
+            .zip(fifo.chunks(SCREEN_WIDTH))
+            .enumerate()
+        {
+            assert_eq!(scanline, fifo, "line {}", line);
+        }
+    }
+}
@@ -0,0 +1,66 @@
+use super::{
+    apply_palette, Ppu, LCDC_BG_ENABLE, LCDC_OBJ_ENABLE, OBJ_BEHIND_BG, SCREEN_WIDTH,
+    WINDOW_X_OFFSET,
+};
+
+// Draws the whole current line in one go using the register values at the end of mode 3.
+// Mid-line register writes are not visible, which is good enough for most games.
+pub fn render_line(ppu: &mut Ppu) {
+    // Raw color indices are kept around because sprite priority depends on them
+    let mut background = [0u8; SCREEN_WIDTH];
+    let mut shades = [0u8; SCREEN_WIDTH];
+
+    // On DMG, clearing LCDC bit 0 blanks both the background and the window
+    if ppu.lcdc & LCDC_BG_ENABLE != 0 {
+        let tile_map = ppu.background_tile_map();
+        let y = ppu.ly.wrapping_add(ppu.scy);
+        for (x, color) in background.iter_mut().enumerate() {
+            *color = ppu.background_color(tile_map, (x as u8).wrapping_add(ppu.scx), y);
+        }
+
+        if ppu.window_visible() {
+            let tile_map = ppu.window_tile_map();
+            let start = ppu.wx as i16 - WINDOW_X_OFFSET as i16;
+            for (x, color) in background
+                .iter_mut()
+                .enumerate()
+                .skip(start.max(0) as usize)
+            {
+                let window_x = (x as i16 - start) as u8;
+                *color = ppu.background_color(tile_map, window_x, ppu.window_line);
+            }
+            ppu.window_line += 1;
+        }
+    }
+
+    for (shade, &color) in shades.iter_mut().zip(background.iter()) {
+        *shade = apply_palette(ppu.bgp, color);
+    }
+
+    if ppu.lcdc & LCDC_OBJ_ENABLE != 0 {
+        // A higher priority sprite hides the ones below it even where it's behind the background
+        let mut occupied = [false; SCREEN_WIDTH];
+        for sprite in ppu.oam_search() {
+            for column in 0..8u8 {
+                let x = sprite.x as i16 - 8 + column as i16;
+                if !(0..SCREEN_WIDTH as i16).contains(&x) || occupied[x as usize] {
+                    continue;
+                }
+                let x = x as usize;
+                let color = ppu.sprite_color(&sprite, column);
+                // Color 0 is transparent for sprites
+                if color == 0 {
+                    continue;
+                }
+                occupied[x] = true;
+                if sprite.attributes & OBJ_BEHIND_BG != 0 && background[x] != 0 {
+                    continue;
+                }
+                shades[x] = apply_palette(ppu.sprite_palette(&sprite), color);
+            }
+        }
+    }
+
+    let start = ppu.ly as usize * SCREEN_WIDTH;
+    ppu.back_buffer[start..start + SCREEN_WIDTH].copy_from_slice(&shades);
+}
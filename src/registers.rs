@@ -16,6 +16,29 @@ pub struct Registers {
 }
 
 impl Registers {
+    // Register contents the DMG boot ROM leaves behind when it hands over to the cartridge
+    pub fn new() -> Registers {
+        Registers {
+            a: 0x01,
+            b: 0x00,
+            c: 0x13,
+            d: 0x00,
+            e: 0xD8,
+            f: FlagRegister::from(0xB0),
+            h: 0x01,
+            l: 0x4D,
+        }
+    }
+
+    pub fn get_af(&self) -> u16 {
+        (self.a as u16) << 8 | u8::from(self.f) as u16
+    }
+
+    pub fn set_af(&mut self, value: u16) {
+        self.a = ((value & 0xFF00) >> 8) as u8;
+        self.f = FlagRegister::from((value & 0x00FF) as u8);
+    }
+
     pub fn get_bc(&self) -> u16 {
         (self.b as u16) << 8 | self.c as u16
     }
@@ -66,3 +89,12 @@ impl std::convert::From<u8> for FlagRegister {
         }
     }
 }
+
+impl std::convert::From<FlagRegister> for u8 {
+    fn from(flag: FlagRegister) -> u8 {
+        (if flag.zero { 1 } else { 0 }) << ZERO_FLAG_BYTE_POSITION
+            | (if flag.subtract { 1 } else { 0 }) << SUBTRACT_FLAG_BYTE_POSITION
+            | (if flag.half_carry { 1 } else { 0 }) << HALF_CARYY_FLAG_BYTE_POSITION
+            | (if flag.carry { 1 } else { 0 }) << CARRY_FLAG_BYTE_POSITION
+    }
+}
@@ -0,0 +1,143 @@
+use std::cell::RefCell;
+use std::rc::Rc;
+
+use super::SerialDevice;
+use crate::cpu::CPU;
+
+#[derive(Default)]
+struct LinkSide {
+    // SB of a Game Boy waiting on the external clock, for the other side to pick up
+    armed: Option<u8>,
+    // Byte clocked in by the other side, not yet seen by this one
+    inbox: Option<u8>,
+}
+
+pub struct LinkPort {
+    sides: Rc<RefCell<[LinkSide; 2]>>,
+    side: usize,
+}
+
+impl LinkPort {
+    // Both ends of a cable between two emulators running in the same process
+    pub fn pair() -> (LinkPort, LinkPort) {
+        let sides = Rc::new(RefCell::new([LinkSide::default(), LinkSide::default()]));
+        (
+            LinkPort {
+                sides: Rc::clone(&sides),
+                side: 0,
+            },
+            LinkPort { sides, side: 1 },
+        )
+    }
+}
+
+impl SerialDevice for LinkPort {
+    // This side drives the clock, so the bytes swap right away if the other side is armed.
+    // Otherwise nothing is listening and the line floats high.
+    fn transfer(&mut self, outgoing: u8) -> Option<u8> {
+        let mut sides = self.sides.borrow_mut();
+        let peer = &mut sides[1 - self.side];
+        match peer.armed.take() {
+            Some(incoming) => {
+                peer.inbox = Some(outgoing);
+                Some(incoming)
+            }
+            None => Some(0xFF),
+        }
+    }
+
+    fn poll(&mut self, outgoing: Option<u8>) -> Option<u8> {
+        let mut sides = self.sides.borrow_mut();
+        let side = &mut sides[self.side];
+        if outgoing.is_none() {
+            // The transfer was cancelled, so nothing should be exchanged anymore
+            side.armed = None;
+            side.inbox = None;
+            return None;
+        }
+        if let Some(incoming) = side.inbox.take() {
+            side.armed = None;
+            return Some(incoming);
+        }
+        side.armed = outgoing;
+        None
+    }
+}
+
+// Steps whichever Game Boy is behind, which keeps both within one instruction of each
+// other and makes linked runs deterministic. Returns the T-cycles the stepped side took.
+pub fn step_lockstep(first: &mut CPU, second: &mut CPU) -> u32 {
+    if first.cycles() <= second.cycles() {
+        first.step()
+    } else {
+        second.step()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::cartridge::header::test_rom;
+    use crate::cartridge::Cartridge;
+    use crate::interrupts::Interrupt;
+    use crate::memory::MemoryBus;
+    use crate::serial::{SB_REGISTER, SC_REGISTER};
+
+    // Generous bound on how long one transfer of 8 bits at 8192 Hz takes
+    const TRANSFER_CYCLES: u64 = 100_000;
+
+    // LD A,data; LDH (SB),A; LD A,control; LDH (SC),A; then JR to itself
+    fn transfer_program(delay: usize, data: u8, control: u8) -> Vec<u8> {
+        let mut program = vec![0x00; delay];
+        program.extend_from_slice(&[
+            0x3E, data, 0xE0, 0x01, 0x3E, control, 0xE0, 0x02, 0x18, 0xFE,
+        ]);
+        program
+    }
+
+    fn linked_cpu(program: &[u8], port: LinkPort) -> CPU {
+        let cartridge = Cartridge::from_bytes(test_rom::with_program(program)).unwrap();
+        let mut bus = MemoryBus::new(cartridge);
+        bus.serial.connect(Box::new(port));
+        CPU::new(bus)
+    }
+
+    fn serial_requested(cpu: &CPU) -> bool {
+        cpu.bus().interrupt_requested(Interrupt::Serial)
+    }
+
+    #[test]
+    fn master_transfer_swaps_both_sb_values() {
+        let (master_port, slave_port) = LinkPort::pair();
+        // The master waits a little so the slave is armed by the time it clocks
+        let mut master = linked_cpu(&transfer_program(16, 0xA5, 0x81), master_port);
+        let mut slave = linked_cpu(&transfer_program(0, 0x5A, 0x80), slave_port);
+
+        while !(serial_requested(&master) && serial_requested(&slave)) {
+            assert!(
+                master.cycles() < TRANSFER_CYCLES,
+                "transfer never completed"
+            );
+            step_lockstep(&mut master, &mut slave);
+        }
+        assert_eq!(master.bus().read_byte(SB_REGISTER), 0x5A);
+        assert_eq!(slave.bus().read_byte(SB_REGISTER), 0xA5);
+        assert_eq!(master.bus().read_byte(SC_REGISTER) & 0x80, 0);
+        assert_eq!(slave.bus().read_byte(SC_REGISTER) & 0x80, 0);
+    }
+
+    #[test]
+    fn slave_transfer_waits_for_a_master() {
+        let (idle_port, slave_port) = LinkPort::pair();
+        // The other end never starts a transfer
+        let mut idle = linked_cpu(&[0x18, 0xFE], idle_port);
+        let mut slave = linked_cpu(&transfer_program(0, 0x5A, 0x80), slave_port);
+
+        while slave.cycles() < TRANSFER_CYCLES {
+            step_lockstep(&mut idle, &mut slave);
+        }
+        assert!(!serial_requested(&slave));
+        assert_eq!(slave.bus().read_byte(SB_REGISTER), 0x5A);
+        assert_eq!(slave.bus().read_byte(SC_REGISTER) & 0x80, 0x80);
+    }
+}
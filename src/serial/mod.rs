@@ -0,0 +1,171 @@
+pub mod link;
+pub mod printer;
+pub mod tcp;
+
+use std::cell::RefCell;
+use std::io;
+use std::rc::Rc;
+
+pub const SB_REGISTER: u16 = 0xFF01;
+pub const SC_REGISTER: u16 = 0xFF02;
+
+const SC_TRANSFER_START: u8 = 0x80;
+const SC_INTERNAL_CLOCK: u8 = 0x01;
+const SC_WRITABLE_MASK: u8 = SC_TRANSFER_START | SC_INTERNAL_CLOCK;
+
+// The internal clock shifts bits at 8192 Hz
+const T_CYCLES_PER_BIT: u32 = 512;
+const BITS_PER_TRANSFER: u8 = 8;
+
+// Whatever sits on the other end of the link port
+pub trait SerialDevice {
+    // Receives the byte the Game Boy shifts out with its internal clock and returns the
+    // byte shifted back in, or None when the answer comes later through `poll`
+    fn transfer(&mut self, outgoing: u8) -> Option<u8>;
+
+    // Polled on every tick, with the byte in SB while a transfer is armed on the external
+    // clock. Returns the answer to a deferred transfer, or the byte clocked in by the other
+    // side once it starts a transfer; devices that never do either keep the default.
+    fn poll(&mut self, _armed: Option<u8>) -> Option<u8> {
+        None
+    }
+
+    // Writes out anything the device still buffers, like a printed page
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+// Records every byte sent, and answers with 0xFF like an unplugged port.
+// Clones share the same buffer, so keep one around to read the output after plugging in the other.
+#[derive(Clone, Default)]
+pub struct CaptureDevice {
+    output: Rc<RefCell<Vec<u8>>>,
+}
+
+impl CaptureDevice {
+    pub fn new() -> CaptureDevice {
+        CaptureDevice::default()
+    }
+
+    pub fn output(&self) -> Vec<u8> {
+        self.output.borrow().clone()
+    }
+
+    // Test ROMs print plain ASCII, anything else is replaced rather than rejected
+    pub fn text(&self) -> String {
+        String::from_utf8_lossy(&self.output.borrow()).into_owned()
+    }
+
+    pub fn clear(&self) {
+        self.output.borrow_mut().clear();
+    }
+}
+
+impl SerialDevice for CaptureDevice {
+    fn transfer(&mut self, outgoing: u8) -> Option<u8> {
+        self.output.borrow_mut().push(outgoing);
+        Some(0xFF)
+    }
+}
+
+pub struct Serial {
+    data: u8,
+    control: u8,
+    device: Box<dyn SerialDevice>,
+    // Byte coming back from the device, shifted into SB one bit at a time
+    incoming: u8,
+    bits_remaining: u8,
+    cycles: u32,
+    // Set while the device hasn't answered a transfer started with the internal clock yet
+    awaiting_reply: bool,
+}
+
+impl Serial {
+    pub fn new() -> Serial {
+        Serial {
+            data: 0,
+            control: 0,
+            device: Box::new(CaptureDevice::new()),
+            incoming: 0xFF,
+            bits_remaining: 0,
+            cycles: 0,
+            awaiting_reply: false,
+        }
+    }
+
+    pub fn connect(&mut self, device: Box<dyn SerialDevice>) {
+        self.device = device;
+    }
+
+    pub fn flush(&mut self) -> io::Result<()> {
+        self.device.flush()
+    }
+
+    // Returns true when a transfer completes, which requests the serial interrupt
+    pub fn tick(&mut self, t_cycles: u32) -> bool {
+        let idle = self.bits_remaining == 0;
+        let armed = (idle
+            && self.control & SC_TRANSFER_START != 0
+            && self.control & SC_INTERNAL_CLOCK == 0)
+            .then_some(self.data);
+        let incoming = self.device.poll(armed);
+        if idle {
+            if let Some(incoming) = incoming {
+                // Either the answer we waited for, or the other side's clock, which shifts
+                // the bits at the same rate as ours would
+                if self.awaiting_reply || armed.is_some() {
+                    self.awaiting_reply = false;
+                    self.start_transfer(incoming);
+                }
+            }
+            return false;
+        }
+        self.cycles += t_cycles;
+        while self.cycles >= T_CYCLES_PER_BIT {
+            self.cycles -= T_CYCLES_PER_BIT;
+            self.data = self.data << 1 | self.incoming >> 7;
+            self.incoming <<= 1;
+            self.bits_remaining -= 1;
+            if self.bits_remaining == 0 {
+                self.control &= !SC_TRANSFER_START;
+                return true;
+            }
+        }
+        false
+    }
+
+    pub fn read(&self, address: u16) -> u8 {
+        match address {
+            SB_REGISTER => self.data,
+            // Unused SC bits read back as 1
+            SC_REGISTER => self.control | !SC_WRITABLE_MASK,
+            _ => 0xFF,
+        }
+    }
+
+    pub fn write(&mut self, address: u16, value: u8) {
+        match address {
+            SB_REGISTER => self.data = value,
+            SC_REGISTER => {
+                self.control = value & SC_WRITABLE_MASK;
+                self.awaiting_reply = false;
+                // With the external clock nothing happens until the other side drives the clock
+                if self.control & SC_TRANSFER_START != 0 && self.control & SC_INTERNAL_CLOCK != 0 {
+                    match self.device.transfer(self.data) {
+                        Some(incoming) => self.start_transfer(incoming),
+                        // The bits only start shifting once the answer is in
+                        None => self.awaiting_reply = true,
+                    }
+                }
+            }
+            _ => {}
+        }
+    }
+
+    fn start_transfer(&mut self, incoming: u8) {
+        self.incoming = incoming;
+        self.bits_remaining = BITS_PER_TRANSFER;
+        self.cycles = 0;
+    }
+}
@@ -0,0 +1,411 @@
+use std::io;
+use std::path::{Path, PathBuf};
+
+use super::SerialDevice;
+use crate::png;
+use crate::ppu::SHADE_RGBA;
+
+const MAGIC_FIRST: u8 = 0x88;
+const MAGIC_SECOND: u8 = 0x33;
+// The printer answers the first byte after the checksum with this to say it's there
+const ALIVE_RESPONSE: u8 = 0x81;
+
+const COMMAND_INIT: u8 = 0x01;
+const COMMAND_PRINT: u8 = 0x02;
+const COMMAND_DATA: u8 = 0x04;
+
+const STATUS_CHECKSUM_ERROR: u8 = 0x01;
+const STATUS_PRINTING: u8 = 0x02;
+const STATUS_IMAGE_FULL: u8 = 0x04;
+const STATUS_UNPROCESSED: u8 = 0x08;
+
+// The printer has 8 KiB of RAM but only ever holds up to 9 bands of 160x16 pixels
+const BAND_SIZE: usize = 640;
+const MAX_BANDS: usize = 9;
+const IMAGE_WIDTH: usize = 160;
+const TILES_PER_ROW: usize = IMAGE_WIDTH / 8;
+const TILE_SIZE: usize = 16;
+// Games wait for the printing flag to clear, so it stays up for a few status checks
+const BUSY_STATUS_CHECKS: u8 = 4;
+// Palette used when the print command leaves it at 0
+const DEFAULT_PALETTE: u8 = 0xE4;
+
+#[derive(Clone, Copy, PartialEq, Eq)]
+enum PacketState {
+    MagicFirst,
+    MagicSecond,
+    Command,
+    Compression,
+    LengthLow,
+    LengthHigh,
+    Data,
+    ChecksumLow,
+    ChecksumHigh,
+    Alive,
+    Status,
+}
+
+// Game Boy Printer on the link port. Every finished print becomes a numbered PNG in the output directory.
+pub struct Printer {
+    output_directory: PathBuf,
+    next_image_number: u32,
+    state: PacketState,
+    command: u8,
+    compressed: bool,
+    length: u16,
+    data: Vec<u8>,
+    checksum: u16,
+    received_checksum: u16,
+    // Tile data received since the last print
+    tiles: Vec<u8>,
+    // RGBA rows printed so far without a bottom margin, which continue on the next print
+    page: Vec<u8>,
+    checksum_error: bool,
+    busy_checks: u8,
+    // First failed save since the last flush, reported from there
+    save_error: Option<io::Error>,
+}
+
+impl Printer {
+    pub fn new<P: AsRef<Path>>(output_directory: P) -> Printer {
+        Printer {
+            output_directory: output_directory.as_ref().to_path_buf(),
+            next_image_number: 1,
+            state: PacketState::MagicFirst,
+            command: 0,
+            compressed: false,
+            length: 0,
+            data: Vec::new(),
+            checksum: 0,
+            received_checksum: 0,
+            tiles: Vec::new(),
+            page: Vec::new(),
+            checksum_error: false,
+            busy_checks: 0,
+            save_error: None,
+        }
+    }
+
+    // Writes out a page still waiting for its bottom margin, and reports any print
+    // that failed to save since the last call
+    pub fn flush(&mut self) -> io::Result<()> {
+        if let Some(error) = self.save_error.take() {
+            return Err(error);
+        }
+        self.save_page()
+    }
+
+    fn status(&self) -> u8 {
+        let mut status = 0;
+        if self.checksum_error {
+            status |= STATUS_CHECKSUM_ERROR;
+        }
+        if self.busy_checks > 0 {
+            status |= STATUS_PRINTING;
+        }
+        if self.tiles.len() >= BAND_SIZE * MAX_BANDS {
+            status |= STATUS_IMAGE_FULL;
+        }
+        if !self.tiles.is_empty() {
+            status |= STATUS_UNPROCESSED;
+        }
+        status
+    }
+
+    fn add_to_checksum(&mut self, byte: u8) {
+        self.checksum = self.checksum.wrapping_add(byte as u16);
+    }
+
+    fn run_command(&mut self) {
+        self.checksum_error = self.checksum != self.received_checksum;
+        if self.checksum_error {
+            return;
+        }
+        match self.command {
+            COMMAND_INIT => {
+                self.tiles.clear();
+                self.busy_checks = 0;
+            }
+            COMMAND_PRINT => {
+                // Sheet count, margins, palette and exposure
+                let margins = self.data.get(1).copied().unwrap_or(0);
+                let palette = match self.data.get(2).copied().unwrap_or(0) {
+                    0 => DEFAULT_PALETTE,
+                    palette => palette,
+                };
+                self.print(palette, margins & 0x0F != 0);
+                self.busy_checks = BUSY_STATUS_CHECKS;
+            }
+            // An empty data packet only marks the end of the image
+            COMMAND_DATA => {
+                let data = std::mem::take(&mut self.data);
+                let tiles = if self.compressed {
+                    decompress(&data)
+                } else {
+                    data
+                };
+                let space = BAND_SIZE * MAX_BANDS - self.tiles.len().min(BAND_SIZE * MAX_BANDS);
+                self.tiles.extend(tiles.into_iter().take(space));
+            }
+            // Status requests count as a status check
+            _ => self.busy_checks = self.busy_checks.saturating_sub(1),
+        }
+    }
+
+    // Renders the buffered tiles onto the page, which is written out once there's a margin after it
+    fn print(&mut self, palette: u8, bottom_margin: bool) {
+        let tiles = std::mem::take(&mut self.tiles);
+        for tile_row in tiles.chunks_exact(TILE_SIZE * TILES_PER_ROW) {
+            for y in 0..8 {
+                for tile in tile_row.chunks_exact(TILE_SIZE) {
+                    let (low, high) = (tile[y * 2], tile[y * 2 + 1]);
+                    for bit in (0..8).rev() {
+                        let color = ((high >> bit) & 0b1) << 1 | ((low >> bit) & 0b1);
+                        let shade = (palette >> (color * 2)) & 0b11;
+                        self.page.extend_from_slice(&SHADE_RGBA[shade as usize]);
+                    }
+                }
+            }
+        }
+        if bottom_margin {
+            if let Err(error) = self.save_page() {
+                self.save_error.get_or_insert(error);
+            }
+        }
+    }
+
+    fn save_page(&mut self) -> io::Result<()> {
+        if self.page.is_empty() {
+            return Ok(());
+        }
+        let path = loop {
+            let path = self
+                .output_directory
+                .join(format!("print_{:03}.png", self.next_image_number));
+            self.next_image_number += 1;
+            // Keep whatever earlier sessions printed
+            if !path.exists() {
+                break path;
+            }
+        };
+        let height = self.page.len() / (IMAGE_WIDTH * 4);
+        png::save_png(path, IMAGE_WIDTH as u32, height as u32, &self.page)?;
+        self.page.clear();
+        Ok(())
+    }
+}
+
+impl SerialDevice for Printer {
+    // Bytes are answered with 0x00 except the two after the checksum,
+    // which carry the alive marker and the status of the packet
+    fn transfer(&mut self, outgoing: u8) -> Option<u8> {
+        let mut response = 0x00;
+        self.state = match self.state {
+            PacketState::MagicFirst if outgoing == MAGIC_FIRST => PacketState::MagicSecond,
+            PacketState::MagicFirst => PacketState::MagicFirst,
+            PacketState::MagicSecond if outgoing == MAGIC_SECOND => PacketState::Command,
+            PacketState::MagicSecond if outgoing == MAGIC_FIRST => PacketState::MagicSecond,
+            PacketState::MagicSecond => PacketState::MagicFirst,
+            PacketState::Command => {
+                self.command = outgoing;
+                self.checksum = outgoing as u16;
+                PacketState::Compression
+            }
+            PacketState::Compression => {
+                self.compressed = outgoing & 0x01 != 0;
+                self.add_to_checksum(outgoing);
+                PacketState::LengthLow
+            }
+            PacketState::LengthLow => {
+                self.length = outgoing as u16;
+                self.add_to_checksum(outgoing);
+                PacketState::LengthHigh
+            }
+            PacketState::LengthHigh => {
+                self.length |= (outgoing as u16) << 8;
+                self.add_to_checksum(outgoing);
+                self.data.clear();
+                if self.length == 0 {
+                    PacketState::ChecksumLow
+                } else {
+                    PacketState::Data
+                }
+            }
+            PacketState::Data => {
+                self.data.push(outgoing);
+                self.add_to_checksum(outgoing);
+                if self.data.len() == self.length as usize {
+                    PacketState::ChecksumLow
+                } else {
+                    PacketState::Data
+                }
+            }
+            PacketState::ChecksumLow => {
+                self.received_checksum = outgoing as u16;
+                PacketState::ChecksumHigh
+            }
+            PacketState::ChecksumHigh => {
+                self.received_checksum |= (outgoing as u16) << 8;
+                self.run_command();
+                PacketState::Alive
+            }
+            PacketState::Alive => {
+                response = ALIVE_RESPONSE;
+                PacketState::Status
+            }
+            PacketState::Status => {
+                response = self.status();
+                PacketState::MagicFirst
+            }
+        };
+        Some(response)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Printer::flush(self)
+    }
+}
+
+// Whatever was printed without a bottom margin is still worth keeping
+impl Drop for Printer {
+    fn drop(&mut self) {
+        let _ = self.save_page();
+    }
+}
+
+// Runs of the same byte are stored as 0x80 | (count - 2) followed by the byte,
+// everything else as (count - 1) followed by the literal bytes
+fn decompress(data: &[u8]) -> Vec<u8> {
+    let mut output = Vec::with_capacity(data.len() * 2);
+    let mut bytes = data.iter().copied();
+    while let Some(control) = bytes.next() {
+        if control & 0x80 != 0 {
+            let count = (control & 0x7F) as usize + 2;
+            if let Some(value) = bytes.next() {
+                output.extend(std::iter::repeat(value).take(count));
+            }
+        } else {
+            output.extend(bytes.by_ref().take(control as usize + 1));
+        }
+    }
+    output
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn failed_print_is_reported_by_flush() {
+        let mut printer = Printer::new("/nonexistent/printer/output");
+        printer.page = vec![0; IMAGE_WIDTH * 4];
+        printer.print(DEFAULT_PALETTE, true);
+        assert!(printer.flush().is_err());
+    }
+
+    #[test]
+    fn flush_without_a_page_does_nothing() {
+        let mut printer = Printer::new("/nonexistent/printer/output");
+        assert!(printer.flush().is_ok());
+    }
+
+    // Sends a whole packet and returns the keep-alive and status bytes it was answered with
+    fn send_packet(printer: &mut Printer, command: u8, compressed: bool, data: &[u8]) -> [u8; 2] {
+        let length = (data.len() as u16).to_le_bytes();
+        let mut body = vec![command, compressed as u8, length[0], length[1]];
+        body.extend_from_slice(data);
+        let checksum = body
+            .iter()
+            .fold(0u16, |sum, &byte| sum.wrapping_add(byte as u16));
+        send_raw(printer, &body, checksum)
+    }
+
+    fn send_raw(printer: &mut Printer, body: &[u8], checksum: u16) -> [u8; 2] {
+        let mut bytes = vec![MAGIC_FIRST, MAGIC_SECOND];
+        bytes.extend_from_slice(body);
+        bytes.extend_from_slice(&checksum.to_le_bytes());
+        for byte in bytes {
+            assert_eq!(printer.transfer(byte), Some(0x00));
+        }
+        [0, 0].map(|byte| printer.transfer(byte).unwrap())
+    }
+
+    #[test]
+    fn prints_a_band_to_a_png() {
+        let directory = std::env::temp_dir().join(format!("gb-em-printer-{}", std::process::id()));
+        std::fs::create_dir_all(&directory).unwrap();
+        let mut printer = Printer::new(&directory);
+
+        assert_eq!(
+            send_packet(&mut printer, COMMAND_INIT, false, &[]),
+            [ALIVE_RESPONSE, 0]
+        );
+        // One band of solid color 3 tiles
+        let band = vec![0xFF; BAND_SIZE];
+        assert_eq!(
+            send_packet(&mut printer, COMMAND_DATA, false, &band),
+            [ALIVE_RESPONSE, STATUS_UNPROCESSED]
+        );
+        send_packet(&mut printer, COMMAND_DATA, false, &[]);
+        // One sheet, a bottom margin, the default palette and exposure
+        assert_eq!(
+            send_packet(
+                &mut printer,
+                COMMAND_PRINT,
+                false,
+                &[0x01, 0x03, 0xE4, 0x40]
+            ),
+            [ALIVE_RESPONSE, STATUS_PRINTING]
+        );
+        let png = std::fs::read(directory.join("print_001.png")).unwrap();
+        std::fs::remove_dir_all(&directory).unwrap();
+
+        // 160x16, with the first pixel black
+        assert_eq!(png[16..24], [0, 0, 0, 160, 0, 0, 0, 16]);
+        assert_eq!(
+            png[41 + 2 + 5 + 1..41 + 2 + 5 + 5],
+            [0x00, 0x00, 0x00, 0xFF]
+        );
+    }
+
+    #[test]
+    fn bad_checksum_drops_the_packet() {
+        let mut printer = Printer::new(std::env::temp_dir());
+        let body = [COMMAND_DATA, 0x00, 0x02, 0x00, 0x12, 0x34];
+        assert_eq!(
+            send_raw(&mut printer, &body, 0x1234),
+            [ALIVE_RESPONSE, STATUS_CHECKSUM_ERROR]
+        );
+        assert!(printer.tiles.is_empty());
+
+        // The next good packet clears the error again
+        assert_eq!(
+            send_packet(&mut printer, COMMAND_INIT, false, &[]),
+            [ALIVE_RESPONSE, 0]
+        );
+    }
+
+    #[test]
+    fn compressed_data_is_expanded() {
+        assert_eq!(
+            decompress(&[0x81, 0xAA, 0x01, 0x12, 0x34]),
+            [0xAA, 0xAA, 0xAA, 0x12, 0x34]
+        );
+
+        let mut printer = Printer::new(std::env::temp_dir());
+        send_packet(&mut printer, COMMAND_DATA, true, &[0xFF, 0x55, 0x00, 0x66]);
+        let mut expected = vec![0x55; 0x81];
+        expected.push(0x66);
+        assert_eq!(printer.tiles, expected);
+    }
+
+    #[test]
+    fn garbage_before_the_magic_bytes_is_ignored() {
+        let mut printer = Printer::new(std::env::temp_dir());
+        for byte in [0x00, 0x33, 0x88, 0x88] {
+            assert_eq!(printer.transfer(byte), Some(0x00));
+        }
+        // A repeated first magic byte keeps waiting for the second one
+        assert!(printer.state == PacketState::MagicSecond);
+    }
+}
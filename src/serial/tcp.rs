@@ -0,0 +1,247 @@
+use std::io::{self, ErrorKind, Read, Write};
+use std::net::{TcpListener, TcpStream, ToSocketAddrs};
+use std::time::{Duration, Instant};
+
+use super::SerialDevice;
+
+// Every message is two bytes, a kind followed by the byte being exchanged
+const MESSAGE_SIZE: usize = 2;
+// Sent by the side driving the clock with its SB
+const TRANSFER_MESSAGE: u8 = 0x01;
+// Answer to a transfer with the other side's SB, or 0xFF when it wasn't armed
+const REPLY_MESSAGE: u8 = 0x02;
+
+// How long the clocking side waits for an answer before giving up on the byte
+const REPLY_TIMEOUT: Duration = Duration::from_secs(1);
+// The serial port polls on every tick, so an idle socket is only read every so often
+const POLL_INTERVAL: u32 = 64;
+
+// Link cable to another emulator process. Transfers never block: the clocking side's
+// bits only start shifting once the answer arrives, and transfers from the other side
+// are answered from `poll`. Once the peer goes away it behaves like an unplugged cable.
+pub struct TcpLink {
+    stream: Option<TcpStream>,
+    received: Vec<u8>,
+    // Bytes the socket couldn't take yet, sent before anything else
+    unsent: Vec<u8>,
+    polls: u32,
+    // When our pending transfer gives up on its answer
+    reply_deadline: Option<Instant>,
+    // Replies to transfers that already timed out, dropped when they show up
+    stale_replies: u32,
+}
+
+impl TcpLink {
+    // Waits for the other emulator to connect
+    pub fn listen<A: ToSocketAddrs>(address: A) -> io::Result<TcpLink> {
+        TcpLink::accept(&TcpListener::bind(address)?)
+    }
+
+    pub fn accept(listener: &TcpListener) -> io::Result<TcpLink> {
+        let (stream, _) = listener.accept()?;
+        TcpLink::from_stream(stream)
+    }
+
+    pub fn connect<A: ToSocketAddrs>(address: A) -> io::Result<TcpLink> {
+        TcpLink::from_stream(TcpStream::connect(address)?)
+    }
+
+    fn from_stream(stream: TcpStream) -> io::Result<TcpLink> {
+        stream.set_nodelay(true)?;
+        stream.set_nonblocking(true)?;
+        Ok(TcpLink {
+            stream: Some(stream),
+            received: Vec::new(),
+            unsent: Vec::new(),
+            polls: 0,
+            reply_deadline: None,
+            stale_replies: 0,
+        })
+    }
+
+    pub fn is_connected(&self) -> bool {
+        self.stream.is_some()
+    }
+
+    fn send(&mut self, kind: u8, value: u8) {
+        if self.stream.is_some() {
+            self.unsent.extend_from_slice(&[kind, value]);
+            self.flush_unsent();
+        }
+    }
+
+    // Writes as much as the socket takes right now. A full send buffer only means the
+    // peer is slow to read, so the rest waits for the next try.
+    fn flush_unsent(&mut self) {
+        let Some(stream) = &mut self.stream else {
+            return;
+        };
+        while !self.unsent.is_empty() {
+            match stream.write(&self.unsent) {
+                Ok(0) => return self.disconnect(),
+                Ok(count) => {
+                    self.unsent.drain(..count);
+                }
+                Err(error) if error.kind() == ErrorKind::Interrupted => {}
+                Err(error) if error.kind() == ErrorKind::WouldBlock => return,
+                Err(_) => return self.disconnect(),
+            }
+        }
+    }
+
+    // Returns the next complete message, reading from the socket if none is buffered
+    fn receive(&mut self) -> Option<(u8, u8)> {
+        if self.received.len() < MESSAGE_SIZE {
+            self.fill();
+        }
+        if self.received.len() < MESSAGE_SIZE {
+            return None;
+        }
+        let message = (self.received[0], self.received[1]);
+        self.received.drain(..MESSAGE_SIZE);
+        Some(message)
+    }
+
+    fn fill(&mut self) {
+        let Some(stream) = &mut self.stream else {
+            return;
+        };
+        let mut buffer = [0; 64];
+        match stream.read(&mut buffer) {
+            Ok(0) => self.disconnect(),
+            Ok(count) => self.received.extend_from_slice(&buffer[..count]),
+            Err(error)
+                if matches!(error.kind(), ErrorKind::WouldBlock | ErrorKind::Interrupted) => {}
+            Err(_) => self.disconnect(),
+        }
+    }
+
+    fn disconnect(&mut self) {
+        self.stream = None;
+        self.received.clear();
+        self.unsent.clear();
+    }
+}
+
+impl SerialDevice for TcpLink {
+    fn transfer(&mut self, outgoing: u8) -> Option<u8> {
+        if self.reply_deadline.take().is_some() {
+            // The game restarted a transfer that was still waiting
+            self.stale_replies += 1;
+        }
+        self.send(TRANSFER_MESSAGE, outgoing);
+        if self.stream.is_none() {
+            return Some(0xFF);
+        }
+        self.reply_deadline = Some(Instant::now() + REPLY_TIMEOUT);
+        None
+    }
+
+    fn poll(&mut self, armed: Option<u8>) -> Option<u8> {
+        if !self.unsent.is_empty() {
+            self.flush_unsent();
+        }
+        // Waiting on an answer is worth a read every tick, otherwise only now and then
+        self.polls += 1;
+        if self.reply_deadline.is_none() && self.polls < POLL_INTERVAL {
+            return None;
+        }
+        self.polls = 0;
+
+        while let Some((kind, value)) = self.receive() {
+            match kind {
+                // Also covers both sides starting a transfer at once. Neither is armed
+                // then, so both read 0xFF.
+                TRANSFER_MESSAGE => {
+                    self.send(REPLY_MESSAGE, armed.unwrap_or(0xFF));
+                    if armed.is_some() {
+                        return Some(value);
+                    }
+                }
+                REPLY_MESSAGE if self.stale_replies > 0 => self.stale_replies -= 1,
+                REPLY_MESSAGE if self.reply_deadline.take().is_some() => return Some(value),
+                _ => {}
+            }
+        }
+
+        let deadline = self.reply_deadline?;
+        if self.stream.is_none() {
+            self.reply_deadline = None;
+            return Some(0xFF);
+        }
+        if Instant::now() >= deadline {
+            self.reply_deadline = None;
+            self.stale_replies += 1;
+            return Some(0xFF);
+        }
+        None
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::thread;
+
+    fn poll_until(link: &mut TcpLink, armed: Option<u8>) -> Option<u8> {
+        let deadline = Instant::now() + REPLY_TIMEOUT * 5;
+        while Instant::now() < deadline {
+            if let Some(incoming) = link.poll(armed) {
+                return Some(incoming);
+            }
+            thread::yield_now();
+        }
+        None
+    }
+
+    #[test]
+    fn bytes_swap_between_two_links() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let address = listener.local_addr().unwrap();
+        let external = thread::spawn(move || {
+            let mut link = TcpLink::accept(&listener).unwrap();
+            poll_until(&mut link, Some(0x42))
+        });
+
+        let mut link = TcpLink::connect(address).unwrap();
+        assert_eq!(link.transfer(0x17), None);
+        assert_eq!(poll_until(&mut link, None), Some(0x42));
+        assert_eq!(external.join().unwrap(), Some(0x17));
+    }
+
+    #[test]
+    fn unarmed_peer_answers_with_ff() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let address = listener.local_addr().unwrap();
+        let idle = thread::spawn(move || {
+            let mut link = TcpLink::accept(&listener).unwrap();
+            // Keeps polling without a transfer armed until the other side hangs up
+            while link.is_connected() {
+                assert_eq!(link.poll(None), None);
+                thread::yield_now();
+            }
+        });
+
+        let mut link = TcpLink::connect(address).unwrap();
+        assert_eq!(link.transfer(0x17), None);
+        assert_eq!(poll_until(&mut link, None), Some(0xFF));
+        drop(link);
+        idle.join().unwrap();
+    }
+
+    #[test]
+    fn disconnected_link_reads_ff() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let address = listener.local_addr().unwrap();
+        let peer = thread::spawn(move || drop(TcpLink::accept(&listener).unwrap()));
+
+        let mut link = TcpLink::connect(address).unwrap();
+        peer.join().unwrap();
+        let transfer = link.transfer(0x17);
+        let incoming = match transfer {
+            Some(incoming) => Some(incoming),
+            None => poll_until(&mut link, None),
+        };
+        assert_eq!(incoming, Some(0xFF));
+    }
+}
@@ -0,0 +1,251 @@
+pub const DIV_REGISTER: u16 = 0xFF04;
+pub const TIMA_REGISTER: u16 = 0xFF05;
+pub const TMA_REGISTER: u16 = 0xFF06;
+pub const TAC_REGISTER: u16 = 0xFF07;
+
+const TAC_ENABLE_BIT: u8 = 0x04;
+const TAC_CLOCK_SELECT_MASK: u8 = 0x03;
+
+// Internal divider value on DMG right after the boot ROM hands over
+const POST_BOOT_DIVIDER: u16 = 0xABCC;
+
+const T_CYCLES_PER_TICK: u32 = 4;
+
+pub struct Timer {
+    // DIV is the upper byte of this counter, which goes up every T-cycle
+    divider: u16,
+    tima: u8,
+    tma: u8,
+    tac: u8,
+    // TIMA overflowed during the last M-cycle and still reads 0x00; the reload
+    // from TMA and the interrupt request happen one M-cycle later
+    overflow_pending: bool,
+    // TIMA was reloaded from TMA during the last M-cycle. Writes to TIMA are
+    // ignored in that window while writes to TMA also land in TIMA.
+    reloading: bool,
+}
+
+impl Timer {
+    pub fn new() -> Timer {
+        Timer {
+            divider: POST_BOOT_DIVIDER,
+            tima: 0,
+            tma: 0,
+            tac: 0,
+            overflow_pending: false,
+            reloading: false,
+        }
+    }
+
+    pub fn divider(&self) -> u16 {
+        self.divider
+    }
+
+    // Returns true when the timer interrupt should be requested
+    pub fn tick(&mut self, t_cycles: u32) -> bool {
+        let mut interrupt = false;
+        for _ in 0..t_cycles / T_CYCLES_PER_TICK {
+            interrupt |= self.tick_m_cycle();
+        }
+        interrupt
+    }
+
+    pub fn read(&self, address: u16) -> u8 {
+        match address {
+            DIV_REGISTER => (self.divider >> 8) as u8,
+            TIMA_REGISTER => self.tima,
+            TMA_REGISTER => self.tma,
+            TAC_REGISTER => self.tac | !(TAC_ENABLE_BIT | TAC_CLOCK_SELECT_MASK),
+            _ => 0xFF,
+        }
+    }
+
+    pub fn write(&mut self, address: u16, value: u8) {
+        match address {
+            // Resetting the divider can produce a falling edge on the selected bit, ticking TIMA
+            DIV_REGISTER => {
+                let signal = self.timer_signal();
+                self.divider = 0;
+                if signal && !self.timer_signal() {
+                    self.increment_tima();
+                }
+            }
+            // Writing during the delay cancels both the reload and the interrupt
+            TIMA_REGISTER if !self.reloading => {
+                self.tima = value;
+                self.overflow_pending = false;
+            }
+            TIMA_REGISTER => {}
+            TMA_REGISTER => {
+                self.tma = value;
+                if self.reloading {
+                    self.tima = value;
+                }
+            }
+            // Disabling the timer or picking another bit works the same way as a DIV reset
+            TAC_REGISTER => {
+                let signal = self.timer_signal();
+                self.tac = value & (TAC_ENABLE_BIT | TAC_CLOCK_SELECT_MASK);
+                if signal && !self.timer_signal() {
+                    self.increment_tima();
+                }
+            }
+            _ => {}
+        }
+    }
+
+    fn tick_m_cycle(&mut self) -> bool {
+        let mut interrupt = false;
+        self.reloading = false;
+        if self.overflow_pending {
+            self.overflow_pending = false;
+            self.tima = self.tma;
+            self.reloading = true;
+            interrupt = true;
+        }
+
+        let signal = self.timer_signal();
+        self.divider = self.divider.wrapping_add(T_CYCLES_PER_TICK as u16);
+        // TIMA counts falling edges of the selected divider bit ANDed with the enable bit
+        if signal && !self.timer_signal() {
+            self.increment_tima();
+        }
+        interrupt
+    }
+
+    fn increment_tima(&mut self) {
+        let (new_value, did_overflow) = self.tima.overflowing_add(1);
+        self.tima = new_value;
+        if did_overflow {
+            self.overflow_pending = true;
+        }
+    }
+
+    fn timer_signal(&self) -> bool {
+        let bit = match self.tac & TAC_CLOCK_SELECT_MASK {
+            0b00 => 9, // 4096 Hz
+            0b01 => 3, // 262144 Hz
+            0b10 => 5, // 65536 Hz
+            _ => 7,    // 16384 Hz
+        };
+        self.tac & TAC_ENABLE_BIT != 0 && (self.divider >> bit) & 0b1 != 0
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // 262144 Hz, TIMA ticks on the falling edge of divider bit 3 every 16 T-cycles
+    const TAC_FASTEST: u8 = TAC_ENABLE_BIT | 0b01;
+
+    // Timer with the divider at 0 and the fastest clock selected
+    fn fast_timer() -> Timer {
+        let mut timer = Timer::new();
+        timer.write(DIV_REGISTER, 0);
+        timer.write(TAC_REGISTER, TAC_FASTEST);
+        timer
+    }
+
+    // Runs up to the M-cycle where TIMA overflows, returning whether an interrupt fired
+    fn overflow(timer: &mut Timer) -> bool {
+        timer.write(TIMA_REGISTER, 0xFF);
+        timer.tick(16)
+    }
+
+    #[test]
+    fn counts_falling_edges_of_the_selected_bit() {
+        let mut timer = fast_timer();
+        timer.tick(12);
+        assert_eq!(timer.read(TIMA_REGISTER), 0);
+        timer.tick(4);
+        assert_eq!(timer.read(TIMA_REGISTER), 1);
+        timer.tick(16 * 9);
+        assert_eq!(timer.read(TIMA_REGISTER), 10);
+    }
+
+    #[test]
+    fn stays_still_while_disabled() {
+        let mut timer = Timer::new();
+        timer.write(TAC_REGISTER, 0b01);
+        timer.tick(1024);
+        assert_eq!(timer.read(TIMA_REGISTER), 0);
+    }
+
+    #[test]
+    fn div_reset_with_selected_bit_high_ticks_tima() {
+        let mut timer = fast_timer();
+        timer.tick(8);
+        timer.write(DIV_REGISTER, 0x12);
+        assert_eq!(timer.read(TIMA_REGISTER), 1);
+        assert_eq!(timer.read(DIV_REGISTER), 0);
+    }
+
+    #[test]
+    fn div_reset_with_selected_bit_low_does_not_tick_tima() {
+        let mut timer = fast_timer();
+        timer.tick(4);
+        timer.write(DIV_REGISTER, 0);
+        assert_eq!(timer.read(TIMA_REGISTER), 0);
+    }
+
+    #[test]
+    fn disabling_with_selected_bit_high_ticks_tima() {
+        let mut timer = fast_timer();
+        timer.tick(8);
+        timer.write(TAC_REGISTER, 0b01);
+        assert_eq!(timer.read(TIMA_REGISTER), 1);
+    }
+
+    #[test]
+    fn switching_to_a_low_bit_ticks_tima() {
+        let mut timer = fast_timer();
+        // Bit 3 is high and bit 9 (4096 Hz) is low
+        timer.tick(8);
+        timer.write(TAC_REGISTER, TAC_ENABLE_BIT);
+        assert_eq!(timer.read(TIMA_REGISTER), 1);
+    }
+
+    #[test]
+    fn overflow_reloads_and_interrupts_one_m_cycle_later() {
+        let mut timer = fast_timer();
+        timer.write(TMA_REGISTER, 0x80);
+        assert!(!overflow(&mut timer));
+        assert_eq!(timer.read(TIMA_REGISTER), 0x00);
+
+        assert!(timer.tick(4));
+        assert_eq!(timer.read(TIMA_REGISTER), 0x80);
+    }
+
+    #[test]
+    fn tima_write_during_delay_cancels_reload_and_interrupt() {
+        let mut timer = fast_timer();
+        timer.write(TMA_REGISTER, 0x80);
+        overflow(&mut timer);
+        timer.write(TIMA_REGISTER, 0x42);
+
+        assert!(!timer.tick(4));
+        assert_eq!(timer.read(TIMA_REGISTER), 0x42);
+    }
+
+    #[test]
+    fn tima_write_during_reload_is_ignored() {
+        let mut timer = fast_timer();
+        timer.write(TMA_REGISTER, 0x80);
+        overflow(&mut timer);
+        timer.tick(4);
+        timer.write(TIMA_REGISTER, 0x42);
+        assert_eq!(timer.read(TIMA_REGISTER), 0x80);
+    }
+
+    #[test]
+    fn tma_write_during_reload_lands_in_tima() {
+        let mut timer = fast_timer();
+        timer.write(TMA_REGISTER, 0x80);
+        overflow(&mut timer);
+        timer.tick(4);
+        timer.write(TMA_REGISTER, 0x33);
+        assert_eq!(timer.read(TIMA_REGISTER), 0x33);
+        assert_eq!(timer.read(TMA_REGISTER), 0x33);
+    }
+}
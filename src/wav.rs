@@ -0,0 +1,215 @@
+use std::fs::File;
+use std::io::{self, BufWriter, Seek, SeekFrom, Write};
+use std::path::{Path, PathBuf};
+
+const BITS_PER_SAMPLE: u16 = 16;
+const FORMAT_PCM: u16 = 1;
+const HEADER_SIZE: u32 = 44;
+// Offsets of the sizes that are only known once recording stops
+const RIFF_SIZE_OFFSET: u64 = 4;
+const DATA_SIZE_OFFSET: u64 = 40;
+
+// 16-bit PCM WAV file written as samples come in. The header gets the final sizes on finish.
+pub struct WavWriter {
+    writer: BufWriter<File>,
+    data_size: u32,
+    finished: bool,
+}
+
+impl WavWriter {
+    pub fn create<P: AsRef<Path>>(
+        path: P,
+        channels: u16,
+        sample_rate: u32,
+    ) -> io::Result<WavWriter> {
+        let mut writer = BufWriter::new(File::create(path)?);
+        let block_align = channels * BITS_PER_SAMPLE / 8;
+
+        writer.write_all(b"RIFF")?;
+        writer.write_all(&(HEADER_SIZE - 8).to_le_bytes())?;
+        writer.write_all(b"WAVE")?;
+        writer.write_all(b"fmt ")?;
+        writer.write_all(&16u32.to_le_bytes())?;
+        writer.write_all(&FORMAT_PCM.to_le_bytes())?;
+        writer.write_all(&channels.to_le_bytes())?;
+        writer.write_all(&sample_rate.to_le_bytes())?;
+        writer.write_all(&(sample_rate * block_align as u32).to_le_bytes())?;
+        writer.write_all(&block_align.to_le_bytes())?;
+        writer.write_all(&BITS_PER_SAMPLE.to_le_bytes())?;
+        writer.write_all(b"data")?;
+        writer.write_all(&0u32.to_le_bytes())?;
+
+        Ok(WavWriter {
+            writer,
+            data_size: 0,
+            finished: false,
+        })
+    }
+
+    // Samples are interleaved when there's more than one channel and clipped to -1.0..=1.0
+    pub fn write_samples(&mut self, samples: &[f32]) -> io::Result<()> {
+        for &sample in samples {
+            let value = (sample.clamp(-1.0, 1.0) * i16::MAX as f32) as i16;
+            self.writer.write_all(&value.to_le_bytes())?;
+        }
+        self.data_size += (samples.len() * 2) as u32;
+        Ok(())
+    }
+
+    pub fn finish(&mut self) -> io::Result<()> {
+        if self.finished {
+            return Ok(());
+        }
+        self.writer.seek(SeekFrom::Start(RIFF_SIZE_OFFSET))?;
+        self.writer
+            .write_all(&(HEADER_SIZE - 8 + self.data_size).to_le_bytes())?;
+        self.writer.seek(SeekFrom::Start(DATA_SIZE_OFFSET))?;
+        self.writer.write_all(&self.data_size.to_le_bytes())?;
+        self.writer.seek(SeekFrom::End(0))?;
+        self.writer.flush()?;
+        self.finished = true;
+        Ok(())
+    }
+}
+
+// A recording that was never finished would otherwise claim to be empty
+impl Drop for WavWriter {
+    fn drop(&mut self) {
+        let _ = self.finish();
+    }
+}
+
+// Records the mixed stereo output, and optionally each channel on its own as
+// `<name>_ch1.wav` through `<name>_ch4.wav` next to the main file
+pub struct AudioRecorder {
+    mixed: WavWriter,
+    channels: Option<[WavWriter; 4]>,
+}
+
+impl AudioRecorder {
+    pub fn create<P: AsRef<Path>>(
+        path: P,
+        sample_rate: u32,
+        separate_channels: bool,
+    ) -> io::Result<AudioRecorder> {
+        let path = path.as_ref();
+        let mixed = WavWriter::create(path, 2, sample_rate)?;
+        let channels = if separate_channels {
+            Some([
+                WavWriter::create(channel_path(path, 1), 1, sample_rate)?,
+                WavWriter::create(channel_path(path, 2), 1, sample_rate)?,
+                WavWriter::create(channel_path(path, 3), 1, sample_rate)?,
+                WavWriter::create(channel_path(path, 4), 1, sample_rate)?,
+            ])
+        } else {
+            None
+        };
+        Ok(AudioRecorder { mixed, channels })
+    }
+
+    pub fn records_channels(&self) -> bool {
+        self.channels.is_some()
+    }
+
+    // Takes what Apu::take_samples and Apu::take_channel_samples returned
+    pub fn record(&mut self, mixed: &[f32], channels: Option<&[Vec<f32>; 4]>) -> io::Result<()> {
+        self.mixed.write_samples(mixed)?;
+        if let (Some(writers), Some(channels)) = (&mut self.channels, channels) {
+            for (writer, samples) in writers.iter_mut().zip(channels) {
+                writer.write_samples(samples)?;
+            }
+        }
+        Ok(())
+    }
+
+    // Finishes every file even when one fails, and returns the first error
+    pub fn finish(&mut self) -> io::Result<()> {
+        let mut result = self.mixed.finish();
+        if let Some(writers) = &mut self.channels {
+            for writer in writers {
+                result = result.and(writer.finish());
+            }
+        }
+        result
+    }
+}
+
+fn channel_path(path: &Path, channel: u8) -> PathBuf {
+    let stem = path.file_stem().unwrap_or_default().to_string_lossy();
+    path.with_file_name(format!("{}_ch{}.wav", stem, channel))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs;
+
+    fn u16_at(data: &[u8], offset: usize) -> u16 {
+        u16::from_le_bytes([data[offset], data[offset + 1]])
+    }
+
+    fn u32_at(data: &[u8], offset: usize) -> u32 {
+        u32::from_le_bytes(data[offset..offset + 4].try_into().unwrap())
+    }
+
+    #[test]
+    fn finished_file_has_the_final_sizes_and_clamped_samples() {
+        let path = std::env::temp_dir().join(format!("gb-em-wav-{}.wav", std::process::id()));
+        let mut writer = WavWriter::create(&path, 2, 48_000).unwrap();
+        writer
+            .write_samples(&[0.0, 1.0, -1.0, 0.5, 2.0, -3.0])
+            .unwrap();
+        writer.finish().unwrap();
+        drop(writer);
+        let data = fs::read(&path).unwrap();
+        fs::remove_file(&path).unwrap();
+
+        assert_eq!(data.len(), HEADER_SIZE as usize + 12);
+        assert_eq!(&data[0..4], b"RIFF");
+        assert_eq!(
+            u32_at(&data, RIFF_SIZE_OFFSET as usize),
+            HEADER_SIZE - 8 + 12
+        );
+        assert_eq!(&data[8..16], b"WAVEfmt ");
+        assert_eq!(u16_at(&data, 20), FORMAT_PCM);
+        assert_eq!(u16_at(&data, 22), 2);
+        assert_eq!(u32_at(&data, 24), 48_000);
+        // Byte rate and block align for 16-bit stereo
+        assert_eq!(u32_at(&data, 28), 48_000 * 4);
+        assert_eq!(u16_at(&data, 32), 4);
+        assert_eq!(u16_at(&data, 34), BITS_PER_SAMPLE);
+        assert_eq!(&data[36..40], b"data");
+        assert_eq!(u32_at(&data, DATA_SIZE_OFFSET as usize), 12);
+
+        let samples: Vec<i16> = data[HEADER_SIZE as usize..]
+            .chunks_exact(2)
+            .map(|bytes| i16::from_le_bytes([bytes[0], bytes[1]]))
+            .collect();
+        assert_eq!(samples, [0, 32767, -32767, 16383, 32767, -32767]);
+    }
+
+    #[test]
+    fn recorder_writes_each_channel_next_to_the_mix() {
+        let path = std::env::temp_dir().join(format!("gb-em-rec-{}.wav", std::process::id()));
+        let mut recorder = AudioRecorder::create(&path, 8_000, true).unwrap();
+        assert!(recorder.records_channels());
+        let channels = [vec![0.25], vec![0.5], vec![], vec![-0.25]];
+        recorder.record(&[0.1, 0.2], Some(&channels)).unwrap();
+        recorder.finish().unwrap();
+        drop(recorder);
+
+        let mixed = fs::read(&path).unwrap();
+        assert_eq!(u32_at(&mixed, DATA_SIZE_OFFSET as usize), 4);
+        for (channel, samples) in (1..=4).zip(&channels) {
+            let channel_path = channel_path(&path, channel);
+            let data = fs::read(&channel_path).unwrap();
+            fs::remove_file(&channel_path).unwrap();
+            assert_eq!(u16_at(&data, 22), 1);
+            assert_eq!(
+                u32_at(&data, DATA_SIZE_OFFSET as usize),
+                samples.len() as u32 * 2
+            );
+        }
+        fs::remove_file(&path).unwrap();
+    }
+}